@@ -0,0 +1,89 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::identity::IdentityKind;
+
+/// Why an identity couldn't decrypt a file
+#[derive(Error, Clone, Debug)]
+pub(crate) enum Reason {
+    #[error("unreadable: {0}")]
+    Unreadable(String),
+    #[error("plugin '{0}' is not installed")]
+    MissingPlugin(String),
+    #[error("wrong type, it can't unwrap any of the stanzas")]
+    WrongType,
+    #[error("the file is not encrypted to it")]
+    NotRecipient,
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// An identity which couldn't decrypt a file
+#[derive(Clone, Debug)]
+pub(crate) struct TriedIdentity {
+    pub(crate) source: PathBuf,
+    /// Type of the identity, unknown when the identity file couldn't be read
+    pub(crate) kind: Option<IdentityKind>,
+    pub(crate) reason: Reason,
+}
+
+impl fmt::Display for TriedIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source.display())?;
+        if let Some(kind) = &self.kind {
+            write!(f, " ({})", kind)?;
+        }
+        write!(f, ": {}", self.reason)
+    }
+}
+
+/// None of the configured identities could decrypt a file
+#[derive(Debug)]
+pub(crate) struct DecryptionError {
+    pub(crate) path: Option<PathBuf>,
+    /// Recipient stanza types found in the header of the file
+    pub(crate) stanza_types: Vec<String>,
+    pub(crate) identities: Vec<TriedIdentity>,
+}
+
+impl DecryptionError {
+    /// Names `path` in `err`, which is returned by decrypting the file at `path`
+    pub(crate) fn for_path(err: anyhow::Error, path: &Path) -> anyhow::Error {
+        match err.downcast::<Self>() {
+            Ok(err) => Self {
+                path: Some(path.into()),
+                ..err
+            }
+            .into(),
+            Err(err) => err.context(format!("Couldn't decrypt '{}'", path.display())),
+        }
+    }
+}
+
+impl fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "Couldn't decrypt '{}'", path.display())?,
+            None => write!(f, "Couldn't decrypt file")?,
+        }
+        writeln!(f, ", it is encrypted to {}", self.stanza_types.join(", "))?;
+        if self.identities.is_empty() {
+            writeln!(f, "No identities are configured")?;
+        } else {
+            writeln!(f, "Identities tried:")?;
+            for identity in &self.identities {
+                writeln!(f, "    {}", identity)?;
+            }
+        }
+        write!(
+            f,
+            "Add an identity the file is encrypted to with `git-agecrypt config add -i <path>`"
+        )
+    }
+}
+
+impl std::error::Error for DecryptionError {}
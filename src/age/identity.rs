@@ -0,0 +1,333 @@
+use std::{
+    ffi::OsStr,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use age::{armor::ArmoredReader, plugin, Identity, IdentityFile, IdentityFileEntry};
+use age_core::format::Stanza;
+use anyhow::{anyhow, bail, Context, Result};
+use thiserror::Error;
+
+use super::{
+    session::{binary_name, find_plugin, PluginSession},
+    Prompt,
+};
+use crate::secret::SecretBytes;
+
+const X25519_TAG: &str = "X25519";
+const SSH_ED25519_TAG: &str = "ssh-ed25519";
+const SSH_RSA_TAG: &str = "ssh-rsa";
+const SCRYPT_TAG: &str = "scrypt";
+
+/// Type of an identity, deciding which header stanzas it can unwrap
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum IdentityKind {
+    X25519,
+    /// SSH key unwrapping stanzas with the given tag
+    Ssh(&'static str),
+    /// Identity handled by the named plugin
+    Plugin(String),
+    /// Passphrase encrypted identity file, its contents are only known after decryption
+    Encrypted,
+}
+
+impl IdentityKind {
+    /// Whether any of `stanzas` may be unwrapped by an identity of this kind
+    ///
+    /// Plugins define their own stanza tags, so they are tried for any stanza which is not
+    /// handled natively by age.
+    pub(crate) fn matches(&self, stanzas: &[Stanza]) -> bool {
+        let mut tags = stanzas
+            .iter()
+            .map(|s| s.tag.as_str())
+            .filter(|tag| !is_grease(tag));
+        match self {
+            Self::X25519 => tags.any(|tag| tag == X25519_TAG),
+            Self::Ssh(ssh_tag) => tags.any(|tag| tag == *ssh_tag),
+            Self::Plugin(_) => tags.any(|tag| !is_native(tag)),
+            Self::Encrypted => tags.next().is_some(),
+        }
+    }
+
+    /// Order of trying identities, hardware backed plugins are the last resort
+    fn priority(&self) -> u8 {
+        match self {
+            Self::X25519 | Self::Ssh(_) => 0,
+            Self::Encrypted => 1,
+            Self::Plugin(_) => 2,
+        }
+    }
+}
+
+impl fmt::Display for IdentityKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X25519 => write!(f, "{}", X25519_TAG),
+            Self::Ssh(tag) => write!(f, "{}", tag),
+            Self::Plugin(name) => write!(f, "plugin {}", name),
+            Self::Encrypted => write!(f, "passphrase protected"),
+        }
+    }
+}
+
+fn is_native(tag: &str) -> bool {
+    [X25519_TAG, SSH_ED25519_TAG, SSH_RSA_TAG, SCRYPT_TAG].contains(&tag)
+}
+
+/// Stanzas added by age to keep its parsers tolerant of unknown stanza types
+fn is_grease(tag: &str) -> bool {
+    tag.ends_with("-grease")
+}
+
+/// Stanza types of a header, excluding grease
+pub(crate) fn stanza_types(stanzas: &[Stanza]) -> Vec<String> {
+    let mut types: Vec<String> = stanzas
+        .iter()
+        .filter(|s| !is_grease(&s.tag))
+        .map(|s| s.tag.clone())
+        .collect();
+    types.dedup();
+    types
+}
+
+/// An identity together with its type and the file it was read from
+pub(crate) struct LoadedIdentity {
+    pub(crate) identity: Box<dyn Identity>,
+    pub(crate) kind: IdentityKind,
+    pub(crate) source: PathBuf,
+    /// Plugin identity, unwrapped by a plugin session for batches of files
+    pub(crate) plugin: Option<plugin::Identity>,
+    /// Recipient of the identity, unknown for plugins and passphrase encrypted identity files
+    pub(crate) recipient: Option<String>,
+}
+
+/// Plugin binary required by an identity file is not installed
+#[derive(Error, Debug)]
+#[error("Plugin '{binary_name}' required by '{}' is not found", .path.display())]
+pub(crate) struct MissingPlugin {
+    pub(crate) binary_name: String,
+    path: PathBuf,
+}
+
+/// Identities from `identities` which may unwrap any of `stanzas`, in the order they should be
+/// tried
+pub(crate) fn select_identities<'a>(
+    identities: &'a [LoadedIdentity],
+    stanzas: &[Stanza],
+) -> Vec<&'a LoadedIdentity> {
+    let mut selected: Vec<&LoadedIdentity> = identities
+        .iter()
+        .filter(|i| i.kind.matches(stanzas))
+        .collect();
+    selected.sort_by_key(|i| i.kind.priority());
+    selected
+}
+
+/// Reads identities in the formats accepted by `age --identity`
+///
+/// Unlike `age::cli_common::read_identities`, passphrases and plugin PINs are asked through
+/// `prompt` and identities are never read from the standard input, which holds the contents of
+/// the filtered file. Plugins are looked up in the directories of `search_path`.
+pub(crate) fn read_identities(
+    filenames: &[impl AsRef<Path>],
+    prompt: &Prompt,
+    search_path: &OsStr,
+) -> Result<Vec<LoadedIdentity>> {
+    let mut identities = vec![];
+    for filename in filenames {
+        read_identity_file(filename.as_ref(), prompt, search_path, &mut identities)?;
+    }
+    Ok(identities)
+}
+
+fn read_identity_file(
+    path: &Path,
+    prompt: &Prompt,
+    search_path: &OsStr,
+    identities: &mut Vec<LoadedIdentity>,
+) -> Result<()> {
+    let mut push = |identity: Box<dyn Identity>, kind, plugin, recipient| {
+        identities.push(LoadedIdentity {
+            identity,
+            kind,
+            source: path.into(),
+            plugin,
+            recipient,
+        })
+    };
+    let contents = fs::read(path)
+        .map(SecretBytes::new)
+        .with_context(|| format!("Couldn't read identity file '{}'", path.display()))?;
+    let filename = path.to_string_lossy().to_string();
+
+    // Passphrase encrypted age identity file, the passphrase is only asked when it is used
+    if age::encrypted::Identity::from_buffer(
+        ArmoredReader::new(&contents[..]),
+        Some(filename.clone()),
+        prompt.clone(),
+        None,
+    )
+    .is_ok()
+    {
+        let identity = age::encrypted::Identity::from_buffer(
+            ArmoredReader::new(io::Cursor::new(contents)),
+            Some(filename),
+            prompt.clone(),
+            None,
+        )?
+        .ok_or_else(|| {
+            anyhow!(
+                "Identity file '{}' is encrypted without a passphrase",
+                path.display()
+            )
+        })?;
+        push(Box::new(identity), IdentityKind::Encrypted, None, None);
+        return Ok(());
+    }
+
+    match age::ssh::Identity::from_buffer(&contents[..], Some(filename)) {
+        Ok(age::ssh::Identity::Unsupported(_)) => {
+            bail!("Unsupported SSH key in '{}'", path.display())
+        }
+        Ok(identity) => {
+            let recipient = age::ssh::Recipient::try_from(identity.clone())
+                .map_err(|e| anyhow!("Unsupported SSH key in '{}': {:?}", path.display(), e))?;
+            let tag = match recipient {
+                age::ssh::Recipient::SshRsa(..) => SSH_RSA_TAG,
+                age::ssh::Recipient::SshEd25519(..) => SSH_ED25519_TAG,
+            };
+            push(
+                Box::new(identity.with_callbacks(prompt.clone())),
+                IdentityKind::Ssh(tag),
+                None,
+                Some(recipient.to_string()),
+            );
+            return Ok(());
+        }
+        Err(_) => {}
+    }
+
+    let identity_file = IdentityFile::from_buffer(&contents[..])
+        .with_context(|| format!("Couldn't parse identity file '{}'", path.display()))?;
+    for entry in identity_file.into_identities() {
+        match entry {
+            IdentityFileEntry::Native(identity) => {
+                let recipient = identity.to_public().to_string();
+                push(
+                    Box::new(identity),
+                    IdentityKind::X25519,
+                    None,
+                    Some(recipient),
+                )
+            }
+            IdentityFileEntry::Plugin(identity) => {
+                let name = identity.plugin();
+                let binary = find_plugin(name, search_path).ok_or_else(|| MissingPlugin {
+                    binary_name: binary_name(name),
+                    path: path.into(),
+                })?;
+                let plugin =
+                    PluginSession::new(name, binary, vec![identity.clone()], prompt.clone());
+                push(
+                    Box::new(plugin),
+                    IdentityKind::Plugin(identity.plugin().into()),
+                    Some(identity),
+                    None,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use assert_fs::{prelude::*, TempDir};
+    use rstest::rstest;
+
+    use super::*;
+    use crate::age::session::default_search_path;
+
+    #[test]
+    fn test_reads_identity_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let first = ::age::x25519::Identity::generate();
+        let second = ::age::x25519::Identity::generate();
+        dir.child("keys.txt").write_str(&format!(
+            "# created: today\n{}\n{}\n",
+            first.to_string().expose_secret(),
+            second.to_string().expose_secret()
+        ))?;
+
+        let identities = read_identities(
+            &[dir.join("keys.txt")],
+            &Prompt::from_env(),
+            &default_search_path(),
+        )?;
+        assert_eq!(identities.len(), 2);
+        Ok(())
+    }
+
+    fn stanza(tag: &str) -> Stanza {
+        Stanza {
+            tag: tag.into(),
+            args: vec![],
+            body: vec![],
+        }
+    }
+
+    #[rstest]
+    #[case::x25519(IdentityKind::X25519, "X25519", true)]
+    #[case::x25519_ssh(IdentityKind::X25519, "ssh-ed25519", false)]
+    #[case::ssh(IdentityKind::Ssh(SSH_ED25519_TAG), "ssh-ed25519", true)]
+    #[case::ssh_other_type(IdentityKind::Ssh(SSH_RSA_TAG), "ssh-ed25519", false)]
+    #[case::plugin(IdentityKind::Plugin("yubikey".into()), "piv-p256", true)]
+    #[case::plugin_native(IdentityKind::Plugin("yubikey".into()), "X25519", false)]
+    #[case::plugin_grease(IdentityKind::Plugin("yubikey".into()), "a-grease", false)]
+    #[case::encrypted(IdentityKind::Encrypted, "ssh-rsa", true)]
+    fn test_kind_matches(#[case] kind: IdentityKind, #[case] tag: &str, #[case] expected: bool) {
+        assert_eq!(kind.matches(&[stanza(tag), stanza("x-grease")]), expected);
+    }
+
+    #[test]
+    fn test_select_identities_tries_plugins_last() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        dir.child("key.txt")
+            .write_str(&format!("{}\n", identity.to_string().expose_secret()))?;
+        let mut identities = vec![LoadedIdentity {
+            identity: Box::new(::age::x25519::Identity::generate()),
+            kind: IdentityKind::Plugin("yubikey".into()),
+            source: "yubikey.txt".into(),
+            plugin: None,
+            recipient: None,
+        }];
+        identities.extend(read_identities(
+            &[dir.join("key.txt")],
+            &Prompt::from_env(),
+            &default_search_path(),
+        )?);
+
+        let stanzas = [stanza("piv-p256"), stanza("X25519")];
+        let selected: Vec<_> = select_identities(&identities, &stanzas)
+            .into_iter()
+            .map(|i| i.kind.clone())
+            .collect();
+        assert_eq!(
+            selected,
+            vec![IdentityKind::X25519, IdentityKind::Plugin("yubikey".into())]
+        );
+        assert!(select_identities(&identities, &[stanza("ssh-rsa")]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_never_reads_stdin() {
+        let Err(err) = read_identities(&["-"], &Prompt::from_env(), &default_search_path()) else {
+            panic!("Identities were read from stdin");
+        };
+        assert!(err.to_string().contains("Couldn't read identity file '-'"));
+    }
+}
@@ -0,0 +1,231 @@
+use std::{
+    env,
+    ffi::OsString,
+    process::{Command, Stdio},
+};
+
+use age::{
+    cli_common::UiCallbacks,
+    secrecy::{ExposeSecret, SecretString},
+    Callbacks,
+};
+
+use crate::secret;
+
+/// Fails prompts instead of asking the user, e.g. when git is run from scripts
+pub(crate) const BATCH_ENV: &str = "GIT_AGECRYPT_BATCH";
+
+/// Program asked for passphrases, PINs and confirmations, takes precedence over `SSH_ASKPASS`
+pub(crate) const ASKPASS_ENV: &str = "GIT_AGECRYPT_ASKPASS";
+
+/// Asks for passphrases, PINs and confirmations needed by identities and plugins
+///
+/// Filters can't use their standard input as it holds the file contents, and GUI git clients
+/// don't have a terminal at all. Prompts are answered by the first available of:
+///
+/// 1. `GIT_AGECRYPT_ASKPASS` or `SSH_ASKPASS`
+/// 2. `pinentry` (passphrases and confirmations only)
+/// 3. the controlling terminal
+///
+/// In batch mode every prompt is refused, which makes decryption fail instead of waiting for
+/// input.
+#[derive(Clone)]
+pub(crate) struct Prompt {
+    batch: bool,
+    askpass: Option<OsString>,
+}
+
+impl Prompt {
+    pub(crate) fn from_env() -> Self {
+        let batch = env::var_os(BATCH_ENV).is_some_and(|v| !v.is_empty() && v != "0");
+        let askpass = [ASKPASS_ENV, "SSH_ASKPASS"]
+            .into_iter()
+            .find_map(|var| env::var_os(var).filter(|v| !v.is_empty()));
+        Self { batch, askpass }
+    }
+
+    fn refuse(&self, description: &str) {
+        if self.batch {
+            eprintln!("git-agecrypt: running in batch mode, refusing to ask: {description}");
+        } else {
+            eprintln!(
+                "git-agecrypt: no terminal, askpass or pinentry program available to ask: {description}"
+            );
+        }
+    }
+}
+
+impl Callbacks for Prompt {
+    fn display_message(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        if self.batch {
+            self.refuse(message);
+            return None;
+        }
+        if let Some(program) = &self.askpass {
+            let status = Command::new(program)
+                .arg(message)
+                .env("SSH_ASKPASS_PROMPT", "confirm")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()
+                .ok()?;
+            return Some(status.success());
+        }
+        if let Some(mut dialog) = pinentry::ConfirmationDialog::with_default_binary() {
+            dialog.with_ok(yes_string);
+            if let Some(no_string) = no_string {
+                dialog.with_not_ok(no_string);
+            }
+            return dialog.confirm(message).ok();
+        }
+        if has_terminal() {
+            return UiCallbacks.confirm(message, yes_string, no_string);
+        }
+        self.refuse(message);
+        None
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        if self.batch {
+            self.refuse(description);
+            return None;
+        }
+        if let Some(program) = &self.askpass {
+            return run_askpass(program, description).map(|answer| answer.expose_secret().clone());
+        }
+        if has_terminal() {
+            return UiCallbacks.request_public_string(description);
+        }
+        self.refuse(description);
+        None
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        if self.batch {
+            self.refuse(description);
+            return None;
+        }
+        if let Some(program) = &self.askpass {
+            return run_askpass(program, description);
+        }
+        if has_pinentry() || has_terminal() {
+            // Uses pinentry when available, falls back to the terminal otherwise
+            return UiCallbacks
+                .request_passphrase(description)
+                .filter(|p| !p.expose_secret().is_empty());
+        }
+        self.refuse(description);
+        None
+    }
+}
+
+/// Runs an `ssh-askpass` compatible program, which prints the answer to its standard output
+///
+/// The answer is read without leaving copies of it in memory, as it's usually a passphrase.
+fn run_askpass(program: &OsString, prompt: &str) -> Option<SecretString> {
+    let mut child = Command::new(program)
+        .arg(prompt)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| log::warn!("Couldn't run askpass program {:?}: {}", program, e))
+        .ok()?;
+    let output = child.stdout.take().map(secret::read_to_end);
+    if !child.wait().ok()?.success() {
+        return None;
+    }
+    let output = output?.ok()?;
+    let answer = std::str::from_utf8(&output).ok()?;
+    let answer = answer.strip_suffix('\n').unwrap_or(answer);
+    Some(SecretString::new(answer.into()))
+}
+
+fn has_pinentry() -> bool {
+    env::var_os("PINENTRY_PROGRAM").is_some()
+        || pinentry::PassphraseInput::with_default_binary().is_some()
+}
+
+#[cfg(unix)]
+fn has_terminal() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .is_ok()
+}
+
+#[cfg(not(unix))]
+fn has_terminal() -> bool {
+    use std::io::IsTerminal;
+    std::io::stderr().is_terminal()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use assert_fs::{prelude::*, TempDir};
+
+    use super::*;
+
+    fn askpass(dir: &TempDir, script: &str) -> Prompt {
+        let program = dir.child("askpass");
+        program
+            .write_str(&format!("#!/bin/sh\n{script}\n"))
+            .unwrap();
+        fs::set_permissions(program.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        Prompt {
+            batch: false,
+            askpass: Some(program.path().into()),
+        }
+    }
+
+    #[test]
+    fn test_batch_refuses_prompts() {
+        let prompt = Prompt {
+            batch: true,
+            askpass: Some("/bin/echo".into()),
+        };
+        assert!(prompt.request_passphrase("PIN").is_none());
+        assert!(prompt.request_public_string("name").is_none());
+        assert_eq!(prompt.confirm("touch?", "yes", Some("no")), None);
+    }
+
+    #[test]
+    fn test_askpass_answers_passphrase() {
+        let dir = TempDir::new().unwrap();
+        let prompt = askpass(&dir, "echo \"answer to $1\"");
+
+        let passphrase = prompt.request_passphrase("PIN").unwrap();
+        assert_eq!(passphrase.expose_secret(), "answer to PIN");
+        assert_eq!(
+            prompt.request_public_string("name").as_deref(),
+            Some("answer to name")
+        );
+    }
+
+    #[test]
+    fn test_askpass_failure_cancels() {
+        let dir = TempDir::new().unwrap();
+        let prompt = askpass(&dir, "exit 1");
+
+        assert!(prompt.request_passphrase("PIN").is_none());
+    }
+
+    #[test]
+    fn test_askpass_confirm() {
+        let dir = TempDir::new().unwrap();
+        let prompt = askpass(
+            &dir,
+            "[ \"$SSH_ASKPASS_PROMPT\" = confirm ] && [ \"$1\" = ok? ]",
+        );
+
+        assert_eq!(prompt.confirm("ok?", "yes", None), Some(true));
+        assert_eq!(prompt.confirm("other?", "yes", None), Some(false));
+    }
+}
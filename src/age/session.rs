@@ -0,0 +1,350 @@
+//! Batch decryption with plugin identities
+//!
+//! age starts the plugin binary for every file it decrypts, so hardware backed identities ask
+//! for their PIN or touch once per file. The `identity-v1` protocol accepts the stanzas of
+//! several files in a single run, which is used here to unwrap the file keys of a batch of
+//! files, e.g. the working tree during `refresh`, with one run per plugin.
+//!
+//! Only the `identity-v1` state machine is implemented here, on top of the connection of
+//! `age-core`. A plugin run ends with the protocol, so files decrypted one at a time still
+//! start the plugin for each of them. Encryption is left to age, which runs `recipient-v1`
+//! for each file as the file key is only generated when the file is encrypted.
+
+use std::{
+    cell::RefCell,
+    env,
+    ffi::{OsStr, OsString},
+    io,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use age::{plugin, Callbacks, DecryptError, Identity};
+use age_core::{
+    format::{FileKey, Stanza},
+    plugin::{Connection, Reply, Response, IDENTITY_V1},
+    secrecy::ExposeSecret,
+};
+use anyhow::{anyhow, Result};
+use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
+
+use super::{identity::LoadedIdentity, Prompt};
+
+const CMD_ADD_IDENTITY: &str = "add-identity";
+const CMD_RECIPIENT_STANZA: &str = "recipient-stanza";
+const CMD_MSG: &str = "msg";
+const CMD_CONFIRM: &str = "confirm";
+const CMD_REQUEST_PUBLIC: &str = "request-public";
+const CMD_REQUEST_SECRET: &str = "request-secret";
+const CMD_FILE_KEY: &str = "file-key";
+const CMD_ERROR: &str = "error";
+
+/// File keys unwrapped by a plugin, `None` when none of the stanzas belonged to it
+pub(crate) type Unwrapped = Option<Result<FileKey>>;
+
+/// Plugin identities grouped by plugin, each plugin is started once per batch of files
+pub(crate) struct PluginSessions {
+    sessions: RefCell<Vec<PluginSession>>,
+    search_path: OsString,
+    prompt: Prompt,
+}
+
+impl PluginSessions {
+    /// Sessions of the plugins of `identities`, plugins are looked up in the directories of
+    /// `search_path`
+    pub(crate) fn new(
+        identities: &[LoadedIdentity],
+        search_path: OsString,
+        prompt: Prompt,
+    ) -> Result<Self> {
+        let rv = Self {
+            sessions: RefCell::new(vec![]),
+            search_path,
+            prompt,
+        };
+        for identity in identities.iter().filter_map(|i| i.plugin.as_ref()) {
+            rv.with_session(identity.plugin(), |s| s.identities.push(identity.clone()))?;
+        }
+        Ok(rv)
+    }
+
+    /// Whether there are no plugin identities
+    pub(crate) fn is_empty(&self) -> bool {
+        self.sessions
+            .borrow()
+            .iter()
+            .all(|s| s.identities.is_empty())
+    }
+
+    /// Calls `f` with the session of the plugin `name`, which is looked up on first use
+    fn with_session<T>(&self, name: &str, f: impl FnOnce(&mut PluginSession) -> T) -> Result<T> {
+        let mut sessions = self.sessions.borrow_mut();
+        let index = match sessions.iter().position(|s| s.name == name) {
+            Some(index) => index,
+            None => {
+                let binary = find_plugin(name, &self.search_path)
+                    .ok_or_else(|| anyhow!("Plugin '{}' is not found", binary_name(name)))?;
+                sessions.push(PluginSession::new(
+                    name,
+                    binary,
+                    vec![],
+                    self.prompt.clone(),
+                ));
+                sessions.len() - 1
+            }
+        };
+        Ok(f(&mut sessions[index]))
+    }
+
+    /// Unwraps the file keys of all `headers`, trying the plugins in order until each file has
+    /// a key
+    pub(crate) fn unwrap_files(&self, headers: &[&[Stanza]]) -> Vec<Unwrapped> {
+        let mut keys: Vec<Unwrapped> = headers.iter().map(|_| None).collect();
+        let sessions = self.sessions.borrow();
+        for session in sessions.iter().filter(|s| !s.identities.is_empty()) {
+            let pending: Vec<usize> = (0..headers.len())
+                .filter(|&i| !matches!(keys[i], Some(Ok(_))))
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+            let batch: Vec<&[Stanza]> = pending.iter().map(|&i| headers[i]).collect();
+            match session.unwrap_files(&batch) {
+                Ok(unwrapped) => {
+                    for (&i, key) in pending.iter().zip(unwrapped) {
+                        if key.is_some() {
+                            keys[i] = key;
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Plugin failed; plugin={}, error={:?}", session.name, e);
+                    for &i in &pending {
+                        if keys[i].is_none() {
+                            keys[i] = Some(Err(anyhow!(
+                                "Running plugin '{}' failed: {}",
+                                session.binary.display(),
+                                e
+                            )));
+                        }
+                    }
+                }
+            }
+        }
+        keys
+    }
+}
+
+/// Identities of a single plugin
+///
+/// Also used on its own as the identity of a plugin identity file, which starts the plugin for
+/// each file it decrypts.
+pub(crate) struct PluginSession {
+    name: String,
+    binary: PathBuf,
+    identities: Vec<plugin::Identity>,
+    prompt: Prompt,
+}
+
+impl PluginSession {
+    pub(crate) fn new(
+        name: &str,
+        binary: PathBuf,
+        identities: Vec<plugin::Identity>,
+        prompt: Prompt,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            binary,
+            identities,
+            prompt,
+        }
+    }
+
+    /// Runs the plugin once to unwrap the file keys of `headers`
+    ///
+    /// The connection is closed after the plugin finished the `identity-v1` protocol, which
+    /// lets the plugin exit on its own.
+    fn unwrap_files(&self, headers: &[&[Stanza]]) -> io::Result<Vec<Unwrapped>> {
+        log::debug!(
+            "Starting plugin session; plugin={}, files={}",
+            self.name,
+            headers.len()
+        );
+        let mut conn = Connection::open(&self.binary, IDENTITY_V1)?;
+
+        conn.unidir_send(|mut phase| {
+            for identity in &self.identities {
+                phase.send(CMD_ADD_IDENTITY, &[&identity.to_string()], &[])?;
+            }
+            for (index, stanzas) in headers.iter().enumerate() {
+                let index = index.to_string();
+                for stanza in stanzas.iter() {
+                    phase.send_stanza(CMD_RECIPIENT_STANZA, &[&index], stanza)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        let mut keys: Vec<Option<FileKey>> = headers.iter().map(|_| None).collect();
+        let mut errors: Vec<String> = vec![];
+        let mut file_errors: Vec<Vec<String>> = headers.iter().map(|_| vec![]).collect();
+        conn.bidir_receive(
+            &[
+                CMD_MSG,
+                CMD_CONFIRM,
+                CMD_REQUEST_PUBLIC,
+                CMD_REQUEST_SECRET,
+                CMD_FILE_KEY,
+                CMD_ERROR,
+            ],
+            |command, reply| match command.tag.as_str() {
+                CMD_MSG | CMD_CONFIRM | CMD_REQUEST_PUBLIC | CMD_REQUEST_SECRET => {
+                    prompt_command(&self.prompt, &command, reply)
+                }
+                CMD_FILE_KEY => {
+                    let index = file_index(&command.args, 0, headers.len());
+                    let key = <[u8; 16]>::try_from(&command.body[..]).ok();
+                    match (index, key) {
+                        (Some(index), Some(key)) => keys[index] = Some(FileKey::from(key)),
+                        _ => errors.push(format!("invalid {} response", CMD_FILE_KEY)),
+                    }
+                    reply.ok(None)
+                }
+                CMD_ERROR => {
+                    let message = String::from_utf8_lossy(&command.body).to_string();
+                    match command.args.first().map(String::as_str) {
+                        Some("stanza") => match file_index(&command.args, 1, headers.len()) {
+                            Some(index) => file_errors[index].push(message),
+                            None => errors.push(message),
+                        },
+                        _ => errors.push(message),
+                    }
+                    reply.ok(None)
+                }
+                _ => unreachable!(),
+            },
+        )?;
+
+        Ok(keys
+            .into_iter()
+            .zip(file_errors)
+            .map(|(key, mut file_errors)| match key {
+                Some(key) => Some(Ok(key)),
+                None => {
+                    file_errors.extend(errors.iter().cloned());
+                    (!file_errors.is_empty()).then(|| {
+                        Err(anyhow!(
+                            "Plugin '{}' couldn't unwrap the file key: {}",
+                            binary_name(&self.name),
+                            file_errors.join(", ")
+                        ))
+                    })
+                }
+            })
+            .collect())
+    }
+}
+
+impl Identity for PluginSession {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.unwrap_stanzas(slice::from_ref(stanza))
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        match self.unwrap_files(&[stanzas]) {
+            Ok(mut keys) => keys
+                .pop()
+                .flatten()
+                .map(|key| key.map_err(|e| DecryptError::Io(io::Error::other(e.to_string())))),
+            Err(e) => Some(Err(DecryptError::Io(e))),
+        }
+    }
+}
+
+/// Answers the commands of plugins asking the user for something
+fn prompt_command<R: io::Read, W: io::Write>(
+    prompt: &Prompt,
+    command: &Stanza,
+    reply: Reply<R, W>,
+) -> Response {
+    let body = String::from_utf8_lossy(&command.body);
+    match command.tag.as_str() {
+        CMD_MSG => {
+            prompt.display_message(&body);
+            reply.ok(None)
+        }
+        CMD_CONFIRM => confirm(prompt, command, reply),
+        CMD_REQUEST_PUBLIC => match prompt.request_public_string(&body) {
+            Some(value) => reply.ok(Some(value.as_bytes())),
+            None => reply.fail(),
+        },
+        CMD_REQUEST_SECRET => match prompt.request_passphrase(&body) {
+            Some(secret) => reply.ok(Some(secret.expose_secret().as_bytes())),
+            None => reply.fail(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+fn confirm<R: io::Read, W: io::Write>(
+    prompt: &Prompt,
+    command: &Stanza,
+    reply: Reply<R, W>,
+) -> Response {
+    let decode = |arg: &String| {
+        BASE64_STANDARD_NO_PAD
+            .decode(arg)
+            .ok()
+            .map(|s| String::from_utf8_lossy(&s).to_string())
+    };
+    let (yes, no) = match &command.args[..] {
+        [yes] => (decode(yes), None),
+        [yes, no, ..] => (decode(yes), decode(no)),
+        [] => return reply.fail(),
+    };
+    let Some(yes) = yes else {
+        return reply.fail();
+    };
+    match prompt.confirm(&String::from_utf8_lossy(&command.body), &yes, no.as_deref()) {
+        Some(value) => reply.ok_with_metadata(&[if value { "yes" } else { "no" }], None),
+        None => reply.fail(),
+    }
+}
+
+fn file_index(args: &[String], position: usize, files: usize) -> Option<usize> {
+    args.get(position)
+        .and_then(|i| i.parse().ok())
+        .filter(|&i| i < files)
+}
+
+pub(crate) fn binary_name(plugin_name: &str) -> String {
+    format!("age-plugin-{}", plugin_name)
+}
+
+/// Directories where plugin binaries are looked up by default, `$PATH` like age does
+pub(crate) fn default_search_path() -> OsString {
+    env::var_os("PATH").unwrap_or_default()
+}
+
+/// Looks up the plugin binary in the directories of `search_path`
+pub(crate) fn find_plugin(plugin_name: &str, search_path: &OsStr) -> Option<PathBuf> {
+    let binary = format!("{}{}", binary_name(plugin_name), env::consts::EXE_SUFFIX);
+    env::split_paths(search_path)
+        .map(|dir| dir.join(&binary))
+        .find(|path| is_executable(path))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
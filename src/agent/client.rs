@@ -0,0 +1,111 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{check_socket, decode_key, encode_key, socket_path, FILE_KEY_BYTES};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) struct Status {
+    pub cached_keys: usize,
+    pub ttl: Duration,
+}
+
+/// Client connecting to the agent for each request
+pub(crate) struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Client of the agent of the current user, if the agent is running
+    ///
+    /// Sockets which could have been created by another user are ignored with a warning.
+    pub(crate) fn from_env() -> Option<Self> {
+        let path = socket_path();
+        if fs::symlink_metadata(&path).is_err() {
+            return None;
+        }
+        match check_socket(&path) {
+            Ok(()) => Some(Self::new(path)),
+            Err(e) => {
+                eprintln!("warning: not using the agent: {:#}", e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Result<Option<[u8; FILE_KEY_BYTES]>> {
+        let response = self.request(&format!("GET {id}"))?;
+        match response.split_once(' ') {
+            Some(("KEY", key)) => {
+                Ok(Some(decode_key(key).ok_or_else(|| {
+                    anyhow!("Invalid key received from agent")
+                })?))
+            }
+            _ if response == "NONE" => Ok(None),
+            _ => bail!("Unexpected response from agent: {}", response),
+        }
+    }
+
+    pub(crate) fn put(&self, id: &str, key: &[u8; FILE_KEY_BYTES]) -> Result<()> {
+        self.expect_ok(&format!("PUT {id} {}", encode_key(key)))
+    }
+
+    pub(crate) fn status(&self) -> Result<Status> {
+        let response = self.request("STATUS")?;
+        let mut parts = response.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("STATUS"), Some(cached_keys), Some(ttl)) => Ok(Status {
+                cached_keys: cached_keys.parse()?,
+                ttl: Duration::from_secs(ttl.parse()?),
+            }),
+            _ => bail!("Unexpected response from agent: {}", response),
+        }
+    }
+
+    pub(crate) fn lock(&self) -> Result<()> {
+        self.expect_ok("LOCK")
+    }
+
+    pub(crate) fn stop(&self) -> Result<()> {
+        self.expect_ok("STOP")
+    }
+
+    fn expect_ok(&self, request: &str) -> Result<()> {
+        match self.request(request)?.as_str() {
+            "OK" => Ok(()),
+            response => bail!("Unexpected response from agent: {}", response),
+        }
+    }
+
+    fn request(&self, request: &str) -> Result<String> {
+        check_socket(&self.path)?;
+        let mut stream = UnixStream::connect(&self.path)
+            .with_context(|| format!("Couldn't connect to agent at '{}'", self.path.display()))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        writeln!(stream, "{}", request)?;
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        let response = response.trim_end();
+        if let Some(err) = response.strip_prefix("ERR ") {
+            bail!("Agent error: {}", err);
+        }
+        Ok(response.into())
+    }
+}
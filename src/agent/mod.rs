@@ -0,0 +1,275 @@
+//! Agent caching unwrapped file keys between filter invocations
+//!
+//! Every filter invocation is a separate process, so without the agent hardware backed
+//! identities (e.g. `age-plugin-yubikey`) prompt for a PIN or a touch on every file. The agent
+//! keeps the file keys of already decrypted files in memory and forgets all of them (locks)
+//! after a period of inactivity.
+//!
+//! The protocol is line based, a client sends a single request and reads a single response:
+//!
+//! - `GET <id>` -> `KEY <hex file key>` or `NONE`
+//! - `PUT <id> <hex file key>` -> `OK`
+//! - `STATUS` -> `STATUS <cached keys> <ttl seconds>`
+//! - `LOCK` -> `OK`
+//! - `STOP` -> `OK`
+//!
+//! Errors are reported as `ERR <message>`.
+
+mod client;
+mod server;
+
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    os::unix::{
+        fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+pub(crate) use client::Client;
+pub(crate) use server::Server;
+
+/// Overrides the location of the agent socket
+pub(crate) const SOCKET_ENV: &str = "GIT_AGECRYPT_AGENT_SOCK";
+
+pub(crate) const FILE_KEY_BYTES: usize = 16;
+
+/// Location of the per-user agent socket
+pub(crate) fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os(SOCKET_ENV) {
+        return path.into();
+    }
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("git-agecrypt"),
+        None => {
+            let user = env::var("USER").unwrap_or_else(|_| "user".into());
+            env::temp_dir().join(format!("git-agecrypt-{user}"))
+        }
+    };
+    dir.join("agent.sock")
+}
+
+/// Starts the agent in the background and waits until it accepts connections
+pub(crate) fn spawn(ttl: Duration) -> Result<Client> {
+    let client = Client::new(socket_path());
+    if client.status().is_ok() {
+        bail!("Agent is already running on '{}'", client.path().display());
+    }
+    let exe = env::current_exe().context("Cannot determine path of git-agecrypt")?;
+    Command::new(exe)
+        .args(["agent", "start", "--foreground", "--ttl"])
+        .arg(ttl.as_secs().to_string())
+        .current_dir("/")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // Detach from the terminal's process group so ^C doesn't stop the agent
+        .process_group(0)
+        .spawn()
+        .context("Couldn't start agent")?;
+
+    for _ in 0..50 {
+        if client.status().is_ok() {
+            return Ok(client);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    bail!(
+        "Agent didn't start listening on '{}'",
+        client.path().display()
+    )
+}
+
+/// Creates the directory of the socket accessible only by the current user
+fn ensure_private_dir(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Couldn't create directory '{}'", dir.display()))?;
+    }
+    check_private_dir(dir)
+}
+
+/// Makes sure that `dir` is a directory of the current user, not accessible by others
+///
+/// The default location of the socket is predictable, another user could create it first to
+/// collect the file keys sent to the agent.
+fn check_private_dir(dir: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(dir)
+        .with_context(|| format!("Couldn't access directory '{}'", dir.display()))?;
+    if !metadata.is_dir() {
+        bail!("'{}' isn't a directory, refusing to use it", dir.display());
+    }
+    check_owner(dir, &metadata)?;
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "Directory '{}' is accessible by other users (mode {:o}), refusing to use it",
+            dir.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+/// Makes sure that the agent socket at `path` was created by the current user in a private
+/// directory, before sending file keys to it
+pub(crate) fn check_socket(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        check_private_dir(dir)?;
+    }
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Couldn't access agent socket '{}'", path.display()))?;
+    if !metadata.file_type().is_socket() {
+        bail!("'{}' isn't a socket, refusing to use it", path.display());
+    }
+    check_owner(path, &metadata)
+}
+
+fn check_owner(path: &Path, metadata: &fs::Metadata) -> Result<()> {
+    // SAFETY: geteuid has no preconditions and can't fail
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid {
+        bail!(
+            "'{}' is owned by another user, refusing to use it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Identifier of an age header under which its file key is cached
+///
+/// The header contains random grease and ephemeral shares, so the identifier is unique for
+/// each encrypted file.
+pub(crate) fn header_id(stanzas: &[age_core::format::Stanza]) -> String {
+    let mut hasher = blake3::Hasher::new();
+    for stanza in stanzas {
+        for part in std::iter::once(&stanza.tag).chain(&stanza.args) {
+            hasher.update(&(part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hasher.update(&(stanza.body.len() as u64).to_le_bytes());
+        hasher.update(&stanza.body);
+    }
+    hasher.finalize().to_hex().to_string()
+}
+
+fn encode_key(key: &[u8; FILE_KEY_BYTES]) -> String {
+    key.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_key(hex: &str) -> Option<[u8; FILE_KEY_BYTES]> {
+    if hex.len() != FILE_KEY_BYTES * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; FILE_KEY_BYTES];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use assert_fs::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_key_encoding() {
+        let key: [u8; FILE_KEY_BYTES] = core::array::from_fn(|i| (i * 17) as u8);
+        assert_eq!(decode_key(&encode_key(&key)), Some(key));
+        assert_eq!(decode_key("abc"), None);
+        assert_eq!(decode_key(&"zz".repeat(FILE_KEY_BYTES)), None);
+    }
+
+    #[test]
+    fn test_socket_is_private() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("agent").join("agent.sock");
+        let _server = Server::bind(&path, Duration::from_secs(60))?;
+
+        let dir_mode = fs::metadata(path.parent().unwrap())?.permissions().mode();
+        let sock_mode = fs::metadata(&path)?.permissions().mode();
+        assert_eq!(dir_mode & 0o777, 0o700);
+        assert_eq!(sock_mode & 0o077, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuses_shared_directory() -> Result<()> {
+        let dir = TempDir::new()?;
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755))?;
+        let path = dir.path().join("agent.sock");
+
+        assert!(Server::bind(&path, Duration::from_secs(60)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_refuses_foreign_socket() -> Result<()> {
+        let dir = TempDir::new()?;
+        let private = dir.path().join("agent");
+        let path = private.join("agent.sock");
+        let server = Server::bind(&path, Duration::from_secs(60))?;
+        thread::spawn(move || server.serve());
+        let client = Client::new(path.clone());
+        client.status()?;
+
+        // Reached through a symlink
+        std::os::unix::fs::symlink(&private, dir.path().join("link"))?;
+        let linked = Client::new(dir.path().join("link").join("agent.sock"));
+        assert!(linked.put("id", &[42u8; FILE_KEY_BYTES]).is_err());
+        std::os::unix::fs::symlink(&path, private.join("link.sock"))?;
+        let linked = Client::new(private.join("link.sock"));
+        assert!(linked.put("id", &[42u8; FILE_KEY_BYTES]).is_err());
+
+        // Directory accessible by other users
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o777))?;
+        assert!(client.put("id", &[42u8; FILE_KEY_BYTES]).is_err());
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o700))?;
+
+        // Directory of another user, only possible to set up as root
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::chown(&private, Some(65534), None)?;
+            assert!(client.put("id", &[42u8; FILE_KEY_BYTES]).is_err());
+            std::os::unix::fs::chown(&private, Some(0), None)?;
+        }
+
+        assert_eq!(client.status()?.cached_keys, 0);
+        client.stop()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_client_server_round_trip() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("agent").join("agent.sock");
+        let server = Server::bind(&path, Duration::from_secs(60))?;
+        thread::spawn(move || server.serve());
+
+        let client = Client::new(path);
+        let key = [42u8; FILE_KEY_BYTES];
+        assert_eq!(client.get("id")?, None);
+        client.put("id", &key)?;
+        assert_eq!(client.get("id")?, Some(key));
+        assert_eq!(client.status()?.cached_keys, 1);
+
+        client.lock()?;
+        assert_eq!(client.get("id")?, None);
+        client.stop()?;
+        Ok(())
+    }
+}
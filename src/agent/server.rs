@@ -0,0 +1,196 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use super::{decode_key, encode_key, ensure_private_dir, Client, FILE_KEY_BYTES};
+
+/// Interval of checking whether the agent has to be locked
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time a connected client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct State {
+    keys: HashMap<String, [u8; FILE_KEY_BYTES]>,
+    ttl: Duration,
+    last_used: Instant,
+}
+
+impl State {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            keys: HashMap::new(),
+            ttl,
+            last_used: Instant::now(),
+        }
+    }
+
+    /// Forgets all keys if the agent wasn't used for `ttl`
+    fn expire(&mut self, now: Instant) {
+        if !self.keys.is_empty() && now.duration_since(self.last_used) >= self.ttl {
+            log::info!("Agent timed out, locking");
+            self.lock();
+        }
+    }
+
+    fn lock(&mut self) {
+        for key in self.keys.values_mut() {
+            key.fill(0);
+        }
+        self.keys.clear();
+    }
+
+    /// Handles a single request, returns the response and whether the agent has to stop
+    fn handle(&mut self, request: &str, now: Instant) -> (String, bool) {
+        self.expire(now);
+        let mut parts = request.split_whitespace();
+        let response = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("GET"), Some(id), None, None) => match self.keys.get(id) {
+                Some(key) => {
+                    self.last_used = now;
+                    format!("KEY {}", encode_key(key))
+                }
+                None => "NONE".into(),
+            },
+            (Some("PUT"), Some(id), Some(key), None) => match decode_key(key) {
+                Some(key) => {
+                    self.last_used = now;
+                    self.keys.insert(id.into(), key);
+                    "OK".into()
+                }
+                None => "ERR invalid file key".into(),
+            },
+            (Some("STATUS"), None, None, None) => {
+                format!("STATUS {} {}", self.keys.len(), self.ttl.as_secs())
+            }
+            (Some("LOCK"), None, None, None) => {
+                self.lock();
+                "OK".into()
+            }
+            (Some("STOP"), None, None, None) => {
+                self.lock();
+                return ("OK".into(), true);
+            }
+            _ => "ERR invalid request".into(),
+        };
+        (response, false)
+    }
+}
+
+/// Agent listening on a Unix socket only accessible by the current user
+pub(crate) struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+    state: Arc<Mutex<State>>,
+}
+
+impl Server {
+    pub(crate) fn bind(path: &Path, ttl: Duration) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            ensure_private_dir(dir)?;
+        }
+        if path.exists() {
+            if Client::new(path.into()).status().is_ok() {
+                anyhow::bail!("Agent is already running on '{}'", path.display());
+            }
+            log::debug!("Removing stale agent socket; path={:?}", path);
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Couldn't listen on '{}'", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(Self {
+            listener,
+            path: path.into(),
+            state: Arc::new(Mutex::new(State::new(ttl))),
+        })
+    }
+
+    /// Serves requests until a `STOP` request is received
+    pub(crate) fn serve(self) -> Result<()> {
+        let state = Arc::downgrade(&self.state);
+        thread::spawn(move || expire_keys(state));
+
+        for stream in self.listener.incoming() {
+            match stream
+                .map_err(anyhow::Error::from)
+                .and_then(|s| self.handle(s))
+            {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => log::warn!("Agent request failed: {:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: UnixStream) -> Result<bool> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let (response, stop) = self
+            .state
+            .lock()
+            .unwrap()
+            .handle(request.trim_end(), Instant::now());
+        writeln!(&stream, "{}", response)?;
+        Ok(stop)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn expire_keys(state: Weak<Mutex<State>>) {
+    while let Some(state) = state.upgrade() {
+        state.lock().unwrap().expire(Instant::now());
+        drop(state);
+        thread::sleep(EXPIRY_CHECK_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locks_after_ttl() {
+        let start = Instant::now();
+        let mut state = State::new(Duration::from_secs(10));
+        let key = encode_key(&[1; FILE_KEY_BYTES]);
+        state.handle(&format!("PUT id {key}"), start);
+
+        let (response, _) = state.handle("GET id", start + Duration::from_secs(9));
+        assert_eq!(response, format!("KEY {key}"));
+        // Using a key extends the time until locking
+        let (response, _) = state.handle("GET id", start + Duration::from_secs(18));
+        assert_eq!(response, format!("KEY {key}"));
+
+        state.expire(start + Duration::from_secs(28));
+        assert_eq!(state.handle("GET id", start).0, "NONE");
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let mut state = State::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(state.handle("PUT id 1234", now).0, "ERR invalid file key");
+        assert_eq!(state.handle("GET", now).0, "ERR invalid request");
+        assert_eq!(state.handle("STOP", now), ("OK".into(), true));
+    }
+}
@@ -1,11 +1,69 @@
+use std::{env, io, path::PathBuf};
+
 use anyhow::Result;
 
-use crate::ctx::Context;
+use crate::{age, ctx::Context};
 
 use super::{internal, public};
 
+#[cfg(unix)]
+use super::args::AgentCommands;
 use super::args::{Args, Commands, InternalCommands, ModifyConfig, PublicCommands, QueryConfig};
 
+/// Runs commands that don't need a repository, returns the other ones
+pub(crate) fn run_standalone(args: Args) -> Result<Option<Args>> {
+    if args.batch {
+        // Prompts are answered deep inside age callbacks, also inherited by plugins
+        env::set_var(age::BATCH_ENV, "1");
+    }
+    match args.command {
+        #[cfg(unix)]
+        Commands::Public(PublicCommands::Agent(c)) => run_agent_command(c).map(|_| None),
+        command => Ok(Some(Args { command, ..args })),
+    }
+}
+
+#[cfg(unix)]
+fn run_agent_command(commands: AgentCommands) -> Result<()> {
+    use std::time::Duration;
+
+    use crate::agent;
+
+    let client = agent::Client::new(agent::socket_path());
+    match commands {
+        AgentCommands::Start {
+            ttl,
+            foreground: true,
+        } => {
+            agent::Server::bind(client.path(), Duration::from_secs(ttl))?.serve()?;
+        }
+        AgentCommands::Start { ttl, .. } => {
+            let client = agent::spawn(Duration::from_secs(ttl))?;
+            println!("Agent started on {}", client.path().display());
+        }
+        AgentCommands::Stop => {
+            client.stop()?;
+            println!("Agent stopped");
+        }
+        AgentCommands::Lock => {
+            client.lock()?;
+            println!("Agent locked");
+        }
+        AgentCommands::Status => match client.status() {
+            Ok(status) => {
+                println!("Agent is running on {}", client.path().display());
+                println!(
+                    "    {} file keys cached, locks after {}s of inactivity",
+                    status.cached_keys,
+                    status.ttl.as_secs()
+                );
+            }
+            Err(_) => println!("Agent is not running"),
+        },
+    }
+    Ok(())
+}
+
 pub(crate) fn run(args: Args, ctx: impl Context) -> Result<()> {
     match args.command {
         Commands::Public(c) => run_public_command(c, ctx),
@@ -16,9 +74,13 @@ pub(crate) fn run(args: Args, ctx: impl Context) -> Result<()> {
 fn run_internal_command(commands: InternalCommands, ctx: impl Context) -> Result<()> {
     let cmd = internal::CommandContext { ctx };
     match commands {
-        InternalCommands::Clean { file } => cmd.clean(file),
-        InternalCommands::Smudge { file } => cmd.smudge(file),
-        InternalCommands::Textconv { path } => cmd.textconv(path),
+        InternalCommands::Clean { file } => {
+            cmd.clean(file, &mut io::stdin().lock(), &mut io::stdout().lock())
+        }
+        InternalCommands::Smudge { file } => {
+            cmd.smudge(file, &mut io::stdin().lock(), &mut io::stdout().lock())
+        }
+        InternalCommands::Textconv { path } => cmd.textconv(path, &mut io::stdout().lock()),
     }
 }
 
@@ -34,24 +96,111 @@ fn run_public_command(commands: PublicCommands, ctx: impl Context) -> Result<()>
         PublicCommands::Status => {
             cmd.status()?;
         }
+        PublicCommands::Refresh { paths } => {
+            cmd.refresh(from_current_dir(paths)?)?;
+        }
+        PublicCommands::Verify { paths } => {
+            cmd.verify(from_current_dir(paths)?)?;
+        }
+        PublicCommands::Trust { recipients, all } => {
+            cmd.trust(recipients, all)?;
+        }
+        PublicCommands::Untrust { recipients } => {
+            cmd.untrust(recipients)?;
+        }
         PublicCommands::Config(cfg) => match cfg {
             super::args::ConfigCommands::Add(what) => match ModifyConfig::from(what) {
-                ModifyConfig::Identity(id) => cmd.add_identity(id)?,
-                ModifyConfig::Recipient(paths, recipients) => {
-                    cmd.add_recipients(recipients, paths)?
-                }
+                ModifyConfig::Identity { path, refresh } => cmd.add_identity(path, refresh)?,
+                ModifyConfig::Recipient {
+                    recipients,
+                    label,
+                    escrow,
+                    recipients_files,
+                    group: Some(group),
+                    ..
+                } => cmd.add_group_recipients(
+                    group,
+                    recipients,
+                    label,
+                    escrow,
+                    from_current_dir(recipients_files)?,
+                )?,
+                ModifyConfig::Recipient {
+                    recipients,
+                    label,
+                    escrow,
+                    recipients_files,
+                    default: true,
+                    ..
+                } => cmd.add_default_recipients(
+                    recipients,
+                    label,
+                    escrow,
+                    from_current_dir(recipients_files)?,
+                )?,
+                ModifyConfig::Recipient {
+                    paths,
+                    recipients,
+                    label,
+                    escrow,
+                    recipients_files,
+                    allow_missing,
+                    ..
+                } => cmd.add_recipients(
+                    recipients,
+                    label,
+                    escrow,
+                    from_current_dir(recipients_files)?,
+                    from_current_dir(paths)?,
+                    allow_missing,
+                )?,
             },
             super::args::ConfigCommands::Remove(what) => match ModifyConfig::from(what) {
-                ModifyConfig::Identity(id) => cmd.remove_identity(id)?,
-                ModifyConfig::Recipient(paths, recipients) => {
-                    cmd.remove_recipients(recipients, paths)?
+                ModifyConfig::Identity { path, .. } => cmd.remove_identity(path)?,
+                ModifyConfig::Recipient {
+                    recipients,
+                    recipients_files,
+                    group: Some(group),
+                    ..
+                } => cmd.remove_group_recipients(
+                    group,
+                    recipients,
+                    from_current_dir(recipients_files)?,
+                )?,
+                ModifyConfig::Recipient {
+                    recipients,
+                    recipients_files,
+                    default: true,
+                    ..
+                } => {
+                    cmd.remove_default_recipients(recipients, from_current_dir(recipients_files)?)?
                 }
+                ModifyConfig::Recipient {
+                    paths,
+                    recipients,
+                    recipients_files,
+                    ..
+                } => cmd.remove_recipients(
+                    recipients,
+                    from_current_dir(recipients_files)?,
+                    from_current_dir(paths)?,
+                )?,
             },
             super::args::ConfigCommands::List(what) => match QueryConfig::from(what) {
                 QueryConfig::Identities => cmd.list_identities()?,
                 QueryConfig::Recipients => cmd.list_recipients()?,
             },
+            super::args::ConfigCommands::Migrate => cmd.migrate_config()?,
         },
+        #[cfg(unix)]
+        PublicCommands::Agent(c) => run_agent_command(c)?,
     }
     Ok(())
 }
+
+/// Paths given on the command line are relative to the current directory, which may be anywhere
+/// in the working tree
+fn from_current_dir(paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let cwd = env::current_dir()?;
+    Ok(paths.into_iter().map(|path| cwd.join(path)).collect())
+}
@@ -6,6 +6,11 @@ use clap::{ArgGroup, Parser, Subcommand};
 #[derive(Parser)]
 #[clap(author, version, about)]
 pub struct Args {
+    /// Fail instead of asking for passphrases, PINs or confirmations, same as setting
+    /// GIT_AGECRYPT_BATCH=1
+    #[arg(long, global = true)]
+    pub batch: bool,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -36,6 +41,64 @@ pub enum PublicCommands {
 
     /// Remove repository specific configuration
     Deinit,
+
+    /// Decrypt files left encrypted in the working tree, e.g. after adding an identity
+    Refresh {
+        /// Files to decrypt, all configured files by default
+        paths: Vec<PathBuf>,
+    },
+
+    /// Check the signatures of the files committed in HEAD
+    Verify {
+        /// Files to check, all configured files by default
+        paths: Vec<PathBuf>,
+    },
+
+    /// Approve recipients for encryption after verifying their keys, lists them if none are given
+    Trust {
+        /// Recipients to trust, given by their key, label or fingerprint
+        recipients: Vec<String>,
+
+        /// Trust every recipient of git-agecrypt.toml
+        #[arg(long, conflicts_with = "recipients")]
+        all: bool,
+    },
+
+    /// Revoke the approval of recipients
+    Untrust {
+        /// Recipients to stop trusting, given by their key, label or fingerprint
+        #[arg(required = true)]
+        recipients: Vec<String>,
+    },
+
+    /// Manage the agent caching decrypted file keys
+    #[cfg(unix)]
+    #[command(subcommand)]
+    Agent(AgentCommands),
+}
+
+#[cfg(unix)]
+#[derive(Subcommand)]
+pub enum AgentCommands {
+    /// Start the agent in the background
+    Start {
+        /// Forget cached keys after this many seconds of inactivity
+        #[arg(long, default_value_t = 900)]
+        ttl: u64,
+
+        /// Don't detach from the terminal
+        #[arg(long)]
+        foreground: bool,
+    },
+
+    /// Stop the running agent
+    Stop,
+
+    /// Forget all cached keys
+    Lock,
+
+    /// Show whether the agent is running
+    Status,
 }
 
 #[derive(Subcommand)]
@@ -48,44 +111,108 @@ pub enum ConfigCommands {
 
     /// List configuration entries
     List(ConfigType),
+
+    /// Rewrite git-agecrypt.toml in the newest configuration format
+    Migrate,
 }
 
 #[derive(clap::Args)]
 #[clap(group(
     ArgGroup::new("config")
-        .args(&["identity", "recipient"])
+        .args(&["identity", "recipient", "recipients_file"])
+        .multiple(true)
         .required(true)
 ))]
 #[clap(group(
     ArgGroup::new("rec")
-        .args(&["recipient"])
-        .requires("path")
+        .args(&["recipient", "recipients_file"])
+        .multiple(true)
+        .conflicts_with("identity")
+        .requires("target")
 ))]
+#[clap(group(ArgGroup::new("target").args(&["path", "default", "group"])))]
 pub struct AddConfig {
     /// Identity usable for decryption
-    #[arg(short, long, num_args = 1.., group = "config")]
+    #[arg(short, long, num_args = 1..)]
     identity: Option<PathBuf>,
 
     /// Recipient for encryption
-    #[arg(short, long, num_args = 1.., group = "config")]
+    #[arg(short, long, num_args = 1..)]
     recipient: Option<Vec<String>>,
 
+    /// Name or email shown instead of the recipient, SSH keys default to their comment
+    #[arg(short, long, requires = "recipient")]
+    label: Option<String>,
+
+    /// Mark the recipients as held in escrow, e.g. an offline recovery key
+    #[arg(long, requires = "recipient")]
+    escrow: bool,
+
+    /// File containing recipients for encryption, one per line
+    #[arg(short = 'R', long, num_args = 1..)]
+    recipients_file: Option<Vec<PathBuf>>,
+
     /// Path to encrypt for the given recipient
     #[arg(short, long, num_args = 1..)]
     path: Option<Vec<PathBuf>>,
+
+    /// Accept paths which don't exist yet, to protect files before they are created
+    #[arg(long, requires = "path")]
+    allow_missing: bool,
+
+    /// Use the recipients for protected paths without a rule of their own
+    #[arg(long)]
+    default: bool,
+
+    /// Add the recipients to a recipient group, assigned to paths by the agecrypt-recipients
+    /// attribute
+    #[arg(short, long)]
+    group: Option<String>,
+
+    /// Decrypt the files left encrypted in the working tree with the added identity, as
+    /// `refresh` does
+    #[arg(long, requires = "identity")]
+    refresh: bool,
 }
 
 pub(crate) enum ModifyConfig {
-    Identity(PathBuf),
-    Recipient(Vec<PathBuf>, Vec<String>),
+    Identity {
+        path: PathBuf,
+        refresh: bool,
+    },
+    Recipient {
+        paths: Vec<PathBuf>,
+        recipients: Vec<String>,
+        label: Option<String>,
+        escrow: bool,
+        recipients_files: Vec<PathBuf>,
+        /// Modify the default rule instead of the rules of `paths`
+        default: bool,
+        /// Modify a recipient group instead of the rules of `paths`
+        group: Option<String>,
+        /// Accept `paths` which don't exist yet
+        allow_missing: bool,
+    },
 }
 
 impl From<AddConfig> for ModifyConfig {
     fn from(val: AddConfig) -> Self {
         if let Some(identity) = val.identity {
-            Self::Identity(identity)
-        } else if let Some(recipients) = val.recipient {
-            Self::Recipient(val.path.unwrap(), recipients)
+            Self::Identity {
+                path: identity,
+                refresh: val.refresh,
+            }
+        } else if val.recipient.is_some() || val.recipients_file.is_some() {
+            Self::Recipient {
+                paths: val.path.unwrap_or_default(),
+                recipients: val.recipient.unwrap_or_default(),
+                label: val.label,
+                escrow: val.escrow,
+                recipients_files: val.recipients_file.unwrap_or_default(),
+                default: val.default,
+                group: val.group,
+                allow_missing: val.allow_missing,
+            }
         } else {
             panic!("Misconfigured config parser")
         }
@@ -95,30 +222,74 @@ impl From<AddConfig> for ModifyConfig {
 #[derive(clap::Args)]
 #[clap(group(
     ArgGroup::new("config")
-        .args(&["identity", "recipient"])
+        .args(&["identity", "recipient", "recipients_file"])
+        .multiple(true)
+))]
+#[clap(group(
+    ArgGroup::new("rec")
+        .args(&["recipient", "recipients_file"])
+        .multiple(true)
+        .conflicts_with("identity")
 ))]
 pub struct RemoveConfig {
     /// Identity usable for decryption
-    #[clap(short, long, group = "config")]
+    #[clap(short, long)]
     identity: Option<PathBuf>,
 
-    /// Recipient for encryption
-    #[clap(short, long, group = "config")]
+    /// Recipient for encryption, given by its key, label or short fingerprint
+    #[clap(short, long)]
     recipient: Option<Vec<String>>,
 
+    /// File containing recipients for encryption
+    #[clap(short = 'R', long)]
+    recipients_file: Option<Vec<PathBuf>>,
+
     /// Path to encrypt for the given recipient
     #[clap(short, long)]
     path: Option<Vec<PathBuf>>,
+
+    /// Remove from the default rule, or the whole rule if no recipients are given
+    #[clap(long, conflicts_with_all = ["path", "identity"])]
+    default: bool,
+
+    /// Remove from a recipient group, or the whole group if no recipients are given
+    #[clap(short, long, conflicts_with_all = ["path", "identity", "default"])]
+    group: Option<String>,
 }
 
 impl From<RemoveConfig> for ModifyConfig {
     fn from(val: RemoveConfig) -> Self {
         if let Some(identity) = val.identity {
-            Self::Identity(identity)
-        } else if let Some(recipients) = val.recipient {
-            Self::Recipient(val.path.unwrap_or_default(), recipients)
+            Self::Identity {
+                path: identity,
+                refresh: false,
+            }
+        } else if val.default
+            || val.group.is_some()
+            || val.recipient.is_some()
+            || val.recipients_file.is_some()
+        {
+            Self::Recipient {
+                paths: val.path.unwrap_or_default(),
+                recipients: val.recipient.unwrap_or_default(),
+                label: None,
+                escrow: false,
+                recipients_files: val.recipients_file.unwrap_or_default(),
+                default: val.default,
+                group: val.group,
+                allow_missing: false,
+            }
         } else if let Some(paths) = val.path {
-            Self::Recipient(paths, vec![])
+            Self::Recipient {
+                paths,
+                recipients: vec![],
+                label: None,
+                escrow: false,
+                recipients_files: vec![],
+                default: false,
+                group: None,
+                allow_missing: false,
+            }
         } else {
             panic!("Misconfigured config parser")
         }
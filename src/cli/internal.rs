@@ -1,20 +1,49 @@
 use std::{
-    fs::File,
-    io::{self, Read, Seek, Write},
+    fs,
+    io::{Read, Write},
     path::{Path, PathBuf},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 use blake3::Hash;
 
-use crate::{age, ctx::Context, git::Error as GitError, git::Repository};
+use crate::{
+    age,
+    config::{AppConfig, NoKeyPolicy, PlaintextPolicy, Recipient, RecipientCheck, SignatureCheck},
+    ctx::Context,
+    frame,
+    git::Error as GitError,
+    git::Repository,
+    secret::{self, SecretBytes},
+    sign,
+};
+
+/// Git config setting the no-key policy of the checkout
+const NO_KEY_CONFIG: &str = "git-agecrypt.config.noKey";
+/// Git attribute setting the no-key policy of the matching paths
+const NO_KEY_ATTRIBUTE: &str = "agecrypt-no-key";
+/// Git attribute assigning recipient groups to the matching paths
+pub(super) const RECIPIENTS_ATTRIBUTE: &str = "agecrypt-recipients";
+/// Git config setting how unencrypted files are checked out
+const PLAINTEXT_CONFIG: &str = "git-agecrypt.config.plaintext";
+/// Git config setting whether files nobody can decrypt are committed
+const RECIPIENT_CHECK_CONFIG: &str = "git-agecrypt.config.recipientCheck";
+/// Git config setting the SSH key signing the committed ciphertexts
+const SIGNING_KEY_CONFIG: &str = "git-agecrypt.config.signingKey";
+/// Git config setting whether files without a valid signature are checked out
+const SIGNATURE_CHECK_CONFIG: &str = "git-agecrypt.config.signatureCheck";
 
 pub(crate) struct CommandContext<C: Context> {
     pub ctx: C,
 }
 
 impl<C: Context> CommandContext<C> {
-    pub(crate) fn clean(&self, file: impl AsRef<Path>) -> Result<()> {
+    pub(crate) fn clean(
+        &self,
+        file: impl AsRef<Path>,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
         log::info!("Encrypting file");
         let file = self.ctx.repo().workdir().join(file);
 
@@ -26,10 +55,22 @@ impl<C: Context> CommandContext<C> {
             log::debug!("No saved hash file found");
         }
 
-        let mut hasher = blake3::Hasher::new();
-        let mut contents = vec![];
-        io::stdin().read_to_end(&mut contents)?;
-        let hash = hasher.update(&contents).finalize();
+        let contents = secret::read_to_end(input)?;
+        if age::is_encrypted(sign::payload(&contents)) {
+            // Files left encrypted on checkout are committed as they are. Other ciphertexts would
+            // skip the trust and recipient checks and signing.
+            if !self.is_in_repository(&file, &contents)? {
+                bail!(
+                    "'{}' is already encrypted, but it isn't the ciphertext in the repository; \
+                     replace it with its plaintext to commit it",
+                    file.display()
+                );
+            }
+            log::info!("File is already encrypted, leaving as is");
+            return Ok(output.write_all(&contents)?);
+        }
+
+        let hash = plaintext_hash(&self.ctx, &contents)?;
 
         let old_hash = Hash::from(existing_hash);
         log::debug!(
@@ -45,109 +86,928 @@ impl<C: Context> CommandContext<C> {
         };
 
         let result = self.get_content(contents, hash, file, saved)?;
-        Ok(io::stdout().write_all(&result)?)
+        Ok(output.write_all(&result)?)
     }
 
     fn get_content(
         &self,
-        contents: Vec<u8>,
+        contents: SecretBytes,
         hash: Hash,
         file: PathBuf,
         saved_content: Option<Vec<u8>>,
     ) -> Result<Vec<u8>> {
         if let Some(saved_content) = saved_content {
-            log::debug!("File didn't change since last encryption, loading from git HEAD");
+            log::debug!("File didn't change since last encryption, loading from cache");
             return Ok(saved_content);
         }
 
         log::debug!("Encrypted content changed, checking decrypted version");
-        let repo_contents = match self.ctx.repo().get_file_contents(&file) {
-            Ok(v) => Some(v),
-            Err(GitError::NotExist(s)) => {
-                log::debug!("{}", s);
-                None
+        let mut candidates = vec![];
+        for (source, repo_contents) in [
+            ("index", self.ctx.repo().get_index_contents(&file)),
+            ("HEAD", self.ctx.repo().get_file_contents(&file)),
+        ] {
+            match repo_contents {
+                Ok(v) if !candidates.contains(&v) => candidates.push(v),
+                Ok(_) => log::debug!("Content in {} is the same as already checked", source),
+                Err(GitError::NotExist(s)) => log::debug!("{}", s),
+                Err(e) => return Err(e.into()),
             }
-            Err(e) => return Err(e.into()),
-        };
+        }
 
-        if let Some(repo_contents) = repo_contents {
-            let identities = self.get_identities()?;
-            let mut cur = io::Cursor::new(repo_contents);
-            let decrypted = age::decrypt(&identities, &mut cur)?.unwrap_or_default();
-            if decrypted == contents {
-                log::debug!("Decrypted content matches, using from working copy");
-                self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-                self.ctx.store_sidecar(&file, "age", cur.get_ref())?;
-                return Ok(cur.into_inner());
+        let session = self.ctx.age_session()?;
+        if !candidates.is_empty() {
+            // Plugins are started once for all candidates
+            let files: Vec<&[u8]> = candidates.iter().map(|c| sign::payload(c)).collect();
+            let results = session.decrypt_all(&files);
+            for (repo_contents, decrypted) in candidates.iter().zip(results) {
+                let decrypted = decrypted.and_then(|d| d.map(frame::unwrap).transpose());
+                let decrypted = decrypted.map_err(|e| age::DecryptionError::for_path(e, &file))?;
+                if decrypted.unwrap_or_default() == contents {
+                    log::debug!("Decrypted content matches, using from repository");
+                    self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
+                    self.ctx.store_sidecar(&file, "age", repo_contents)?;
+                    return Ok(repo_contents.clone());
+                }
             }
         }
 
         log::debug!("File changed since last encryption, re-encrypting");
 
         let cfg = self.ctx.config()?;
-        let public_keys = cfg.get_public_keys(&file)?;
+        let groups = self.recipient_groups(&file)?;
+        let recipients = cfg.get_recipients(&file, groups.as_deref())?;
+        self.check_trusted(&file, &recipients)?;
+        let public_keys: Vec<&str> = recipients.iter().map(|r| r.key()).collect();
+        self.check_recipients(&cfg, &file, &public_keys)?;
 
-        let res = age::encrypt(public_keys, &mut &contents[..])?;
+        let compress = cfg.compression(&file, groups.as_deref());
+        let padding = cfg.padding(&file, groups.as_deref());
+        let armor = cfg.armor(&file, groups.as_deref());
+        let framed = frame::wrap(contents, compress, padding)
+            .with_context(|| format!("Couldn't prepare '{}' for encryption", file.display()))?;
+        let res = session
+            .encrypt(&public_keys, &mut &framed[..], armor)
+            .with_context(|| {
+                let recipients: Vec<String> = recipients.iter().map(|r| r.to_string()).collect();
+                format!(
+                    "Couldn't encrypt '{}' for {}",
+                    file.display(),
+                    recipients.join(", ")
+                )
+            })?;
+        let res = self.sign(&cfg, res)?;
         self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
         self.ctx.store_sidecar(&file, "age", &res)?;
         Ok(res)
     }
 
-    fn get_identities(&self) -> Result<Vec<String>> {
-        log::debug!("Loading identities from config");
-        let all_identities = self.ctx.repo().list_config("identity")?;
-        log::debug!(
-            "Loaded identities from config; identities='{:?}'",
-            all_identities
+    /// Whether `blob` is the ciphertext of `file` in the index, in `HEAD` or in the sidecar files
+    fn is_in_repository(&self, file: &Path, blob: &[u8]) -> Result<bool> {
+        if self.ctx.load_sidecar(file, "age")?.as_deref() == Some(blob) {
+            return Ok(true);
+        }
+        for contents in [
+            self.ctx.repo().get_index_contents(file),
+            self.ctx.repo().get_file_contents(file),
+        ] {
+            match contents {
+                Ok(contents) if contents == blob => return Ok(true),
+                Ok(_) | Err(GitError::NotExist(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Prepends a signature made with the configured signing key, if any, to `ciphertext`
+    fn sign(&self, cfg: &AppConfig, ciphertext: Vec<u8>) -> Result<Vec<u8>> {
+        match self.ctx.repo().get_config(SIGNING_KEY_CONFIG) {
+            Ok(key) => sign::sign(Path::new(&key), &ciphertext),
+            Err(GitError::NotExist(_)) => {
+                if cfg.allowed_signers().is_some() {
+                    eprintln!(
+                        "warning: git-agecrypt.toml lists allowed signers but no signing key is \
+                         set in '{}', committing an unsigned file",
+                        SIGNING_KEY_CONFIG
+                    );
+                }
+                Ok(ciphertext)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Refuses to encrypt `file` to recipients which weren't approved in this checkout, e.g.
+    /// ones added to `git-agecrypt.toml` by someone else
+    ///
+    /// Nothing is trusted in a fresh checkout, the recipients have to be approved with
+    /// `git-agecrypt trust` first. Filters never change the trust store.
+    fn check_trusted(&self, file: &Path, recipients: &[Recipient]) -> Result<()> {
+        let trusted = self.ctx.trusted_recipients().list()?;
+        let untrusted: Vec<String> = recipients
+            .iter()
+            .filter(|r| !trusted.iter().any(|t| t.same_key(r)))
+            .map(describe)
+            .collect();
+        if untrusted.is_empty() {
+            return Ok(());
+        }
+        let hint = if trusted.is_empty() {
+            "no recipients are trusted in this checkout yet, verify their keys and approve them \
+             with `git-agecrypt trust --all` or one by one with `git-agecrypt trust`"
+        } else {
+            "verify their keys and approve them with `git-agecrypt trust`"
+        };
+        bail!(
+            "Refusing to encrypt '{}' to recipients which aren't trusted: {}; {}",
+            file.display(),
+            untrusted.join(", "),
+            hint
         );
-        Ok(all_identities)
     }
 
-    pub(crate) fn smudge(&self, file: impl AsRef<Path>) -> Result<()> {
+    /// Makes sure that one of the local identities or an escrow recipient can decrypt `file`
+    /// once it is encrypted to `public_keys`
+    fn check_recipients(&self, cfg: &AppConfig, file: &Path, public_keys: &[&str]) -> Result<()> {
+        if public_keys.iter().any(|k| cfg.is_escrow(k)) {
+            return Ok(());
+        }
+        match self.ctx.age_session()?.can_decrypt(public_keys) {
+            Some(true) => return Ok(()),
+            Some(false) => {}
+            None => {
+                log::debug!(
+                    "The recipients of the identities are unknown, skipping recipient check"
+                );
+                return Ok(());
+            }
+        }
+
+        let fingerprints: Vec<String> = public_keys.iter().map(|k| age::fingerprint(k)).collect();
+        let msg = format!(
+            "None of the identities or escrow recipients can decrypt '{}' encrypted to {}",
+            file.display(),
+            fingerprints.join(", ")
+        );
+        let check = match self.ctx.repo().get_config(RECIPIENT_CHECK_CONFIG) {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("Invalid '{}' setting", RECIPIENT_CHECK_CONFIG))?,
+            Err(GitError::NotExist(_)) => RecipientCheck::default(),
+            Err(e) => return Err(e.into()),
+        };
+        match check {
+            RecipientCheck::Warn => eprintln!("warning: {}", msg),
+            RecipientCheck::Refuse => bail!(msg),
+        }
+        Ok(())
+    }
+
+    /// Policy for `file` when none of the identities can decrypt it
+    ///
+    /// The rule of the path, or else its recipient groups, take precedence over the
+    /// `agecrypt-no-key` attribute, which in turn takes precedence over the
+    /// `git-agecrypt.config.noKey` setting.
+    fn no_key_policy(&self, file: Option<&Path>) -> Result<NoKeyPolicy> {
+        if let Some(file) = file {
+            let groups = self.recipient_groups(file)?;
+            if let Some(policy) = self.ctx.config()?.no_key_policy(file, groups.as_deref()) {
+                return Ok(policy);
+            }
+            if let Some(value) = self.ctx.repo().get_attribute(file, NO_KEY_ATTRIBUTE)? {
+                return value
+                    .parse()
+                    .with_context(|| format!("Invalid '{}' attribute", NO_KEY_ATTRIBUTE));
+            }
+        }
+        match self.ctx.repo().get_config(NO_KEY_CONFIG) {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("Invalid '{}' setting", NO_KEY_CONFIG)),
+            Err(GitError::NotExist(_)) => Ok(NoKeyPolicy::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Recipient groups assigned to `file` by the `agecrypt-recipients` attribute
+    fn recipient_groups(&self, file: &Path) -> Result<Option<String>> {
+        match self
+            .ctx
+            .repo()
+            .get_attribute(file, RECIPIENTS_ATTRIBUTE)?
+            .as_deref()
+        {
+            None | Some("unset") => Ok(None),
+            Some("set") => bail!(
+                "The '{}' attribute needs the names of recipient groups, e.g. {}=ops,backend",
+                RECIPIENTS_ATTRIBUTE,
+                RECIPIENTS_ATTRIBUTE
+            ),
+            Some(groups) => Ok(Some(groups.into())),
+        }
+    }
+
+    /// Policy for checking out files which aren't encrypted with age
+    fn plaintext_policy(&self) -> Result<PlaintextPolicy> {
+        match self.ctx.repo().get_config(PLAINTEXT_CONFIG) {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("Invalid '{}' setting", PLAINTEXT_CONFIG)),
+            Err(GitError::NotExist(_)) => Ok(PlaintextPolicy::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn smudge(
+        &self,
+        file: impl AsRef<Path>,
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<()> {
         log::info!("Decrypting file");
         let file = self.ctx.repo().workdir().join(file);
 
         let mut encrypted = vec![];
-        io::stdin().read_to_end(&mut encrypted)?;
-        let mut cur = io::Cursor::new(encrypted);
-        let all_identities = self.get_identities()?;
-        if let Some(rv) = age::decrypt(&all_identities, &mut cur)? {
+        input.read_to_end(&mut encrypted)?;
+        let ciphertext = sign::payload(&encrypted);
+        if age::is_encrypted(ciphertext) {
+            check_signature(&self.ctx, &self.ctx.config()?, &file, &encrypted)?;
+        }
+        let decrypted = match self.ctx.age_session()?.decrypt(&mut &ciphertext[..]) {
+            Ok(decrypted) => decrypted,
+            Err(e) if e.is::<age::DecryptionError>() => {
+                let e = age::DecryptionError::for_path(e, &file);
+                if self.no_key_policy(Some(&file))? == NoKeyPolicy::Fail {
+                    return Err(e);
+                }
+                log::info!("{}", e);
+                eprintln!(
+                    "warning: no identity can decrypt '{}', leaving it encrypted",
+                    file.display()
+                );
+                return Ok(output.write_all(&encrypted)?);
+            }
+            Err(e) => return Err(age::DecryptionError::for_path(e, &file)),
+        };
+        if let Some(rv) = decrypted {
             log::info!("Decrypted file");
-            let mut hasher = blake3::Hasher::new();
-            let hash = hasher.update(&rv).finalize();
+            let rv = frame::unwrap(rv).map_err(|e| age::DecryptionError::for_path(e, &file))?;
+            let hash = plaintext_hash(&self.ctx, &rv)?;
 
             log::debug!("Storing hash for file; hash={:?}", hash.to_hex().as_str(),);
             self.ctx.store_sidecar(&file, "hash", hash.as_bytes())?;
-            self.ctx.store_sidecar(&file, "age", cur.get_ref())?;
+            self.ctx.store_sidecar(&file, "age", &encrypted)?;
 
-            Ok(io::stdout().write_all(&rv)?)
+            Ok(output.write_all(&rv)?)
         } else {
-            bail!("Input isn't encrypted")
+            // Committed before the file was protected, e.g. when checking out old commits
+            match self.plaintext_policy()? {
+                PlaintextPolicy::Error => {
+                    bail!("Input of '{}' isn't encrypted with age", file.display())
+                }
+                PlaintextPolicy::Warn => eprintln!(
+                    "warning: '{}' isn't encrypted with age, checking it out as it is",
+                    file.display()
+                ),
+                PlaintextPolicy::PassThrough => {
+                    log::info!("File isn't encrypted with age, checking it out as it is")
+                }
+            }
+            Ok(output.write_all(&encrypted)?)
         }
     }
 
-    pub(crate) fn textconv(&self, path: impl AsRef<Path>) -> Result<()> {
+    pub(crate) fn textconv(&self, path: impl AsRef<Path>, output: &mut impl Write) -> Result<()> {
         log::info!("Decrypting file to show in diff");
 
-        let all_identities: Vec<String> = self
+        let path = path.as_ref();
+        // Working copies are passed as well, which are plaintexts
+        let contents = SecretBytes::new(fs::read(path)?);
+        let decrypted = match self
             .ctx
-            .age_identities()
-            .list()?
-            .into_iter()
-            .map(|i| i.path)
-            .collect();
-
-        let mut f = File::open(path)?;
-        let result = if let Some(rv) = age::decrypt(&all_identities, &mut f)? {
+            .age_session()?
+            .decrypt(&mut sign::payload(&contents))
+        {
+            Ok(decrypted) => decrypted,
+            // Git passes a temporary file, so only the policy of the checkout applies
+            Err(e)
+                if e.is::<age::DecryptionError>()
+                    && self.no_key_policy(None)? == NoKeyPolicy::LeaveEncrypted =>
+            {
+                log::info!("{}", e);
+                None
+            }
+            Err(e) => return Err(age::DecryptionError::for_path(e, path)),
+        };
+        let result = if let Some(rv) = decrypted {
             log::info!("Decrypted file to show in diff");
-            rv
+            frame::unwrap(rv).map_err(|e| age::DecryptionError::for_path(e, path))?
         } else {
             log::info!("File isn't encrypted, probably a working copy; showing as is.");
-            f.rewind()?;
-            let mut buff = vec![];
-            f.read_to_end(&mut buff)?;
-            buff
+            contents
+        };
+        Ok(output.write_all(&result)?)
+    }
+}
+
+/// Hash of `contents` stored in the sidecar files, keyed with the key of the checkout
+pub(super) fn plaintext_hash(ctx: &impl Context, contents: &[u8]) -> Result<Hash> {
+    Ok(blake3::keyed_hash(&ctx.sidecar_key()?, contents))
+}
+
+/// Checks that `blob`, the committed ciphertext of `file`, is signed by one of the allowed
+/// signers of the configuration, if any
+pub(super) fn check_signature(
+    ctx: &impl Context,
+    cfg: &AppConfig,
+    file: &Path,
+    blob: &[u8],
+) -> Result<()> {
+    let Some(allowed_signers) = cfg.allowed_signers() else {
+        return Ok(());
+    };
+    let msg = match sign::split(blob) {
+        (Some(signature), ciphertext) => {
+            match sign::verify(&allowed_signers, signature, ciphertext) {
+                Ok(principal) => {
+                    log::info!("'{}' is signed by {}", file.display(), principal);
+                    return Ok(());
+                }
+                Err(e) => format!("Bad signature of '{}': {:#}", file.display(), e),
+            }
+        }
+        (None, _) => format!("'{}' isn't signed by an allowed signer", file.display()),
+    };
+    let check = match ctx.repo().get_config(SIGNATURE_CHECK_CONFIG) {
+        Ok(value) => value
+            .parse()
+            .with_context(|| format!("Invalid '{}' setting", SIGNATURE_CHECK_CONFIG))?,
+        Err(GitError::NotExist(_)) => SignatureCheck::default(),
+        Err(e) => return Err(e.into()),
+    };
+    match check {
+        SignatureCheck::Warn => eprintln!("warning: {}", msg),
+        SignatureCheck::Refuse => bail!(msg),
+    }
+    Ok(())
+}
+
+/// Describes `recipient` for verifying it out of band, by its label, long fingerprint and key
+pub(super) fn describe(recipient: &Recipient) -> String {
+    let key = age::recipient_key(recipient.key());
+    match recipient.label() {
+        Some(label) => format!("{} ({}) {}", label, recipient.long_fingerprint(), key),
+        None => format!("{} ({})", key, recipient.long_fingerprint()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::age::secrecy::ExposeSecret;
+    use anyhow::Result;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use rstest::{fixture, rstest};
+
+    use super::*;
+    use crate::{ctx::MemoryContext, git::MemoryRepository};
+
+    const PLAINTEXT: &[u8] = b"secret contents";
+
+    struct Fixture {
+        cmd: CommandContext<MemoryContext>,
+        dir: TempDir,
+        identity: ::age::x25519::Identity,
+    }
+
+    impl Fixture {
+        fn ctx(&self) -> &MemoryContext {
+            &self.cmd.ctx
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("secret.txt")
+        }
+
+        fn identity_file(&self) -> PathBuf {
+            self.dir.join("identity.txt")
+        }
+
+        fn add_identity(&self) -> Result<()> {
+            self.ctx().repo().add_config(
+                "git-agecrypt.config.identity",
+                self.identity_file().to_str().unwrap(),
+            )?;
+            Ok(())
+        }
+
+        fn encrypt(&self, contents: &[u8]) -> Result<Vec<u8>> {
+            age::encrypt(&[self.identity.to_public().to_string()], &mut &contents[..])
+        }
+
+        fn decrypt(&self, contents: &[u8]) -> Result<Vec<u8>> {
+            Ok(age::decrypt(&[self.identity_file()], &mut &contents[..])?
+                .unwrap()
+                .to_vec())
+        }
+
+        /// Sets the configuration with its recipients approved, as by `git-agecrypt trust --all`
+        fn set_config(&self, contents: &str) {
+            self.ctx().set_config_contents(contents);
+            let mut store = self.ctx().trusted_recipients();
+            for recipient in self.ctx().config().unwrap().recipients().unwrap() {
+                match store.add(recipient) {
+                    Ok(()) | Err(crate::config::Error::AlreadyExists(_)) => {}
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+
+        fn clean(&self, contents: &[u8]) -> Result<Vec<u8>> {
+            let mut output = vec![];
+            self.cmd
+                .clean(self.path(), &mut &contents[..], &mut output)?;
+            Ok(output)
+        }
+
+        fn smudge(&self, contents: &[u8]) -> Result<Vec<u8>> {
+            let mut output = vec![];
+            self.cmd
+                .smudge(self.path(), &mut &contents[..], &mut output)?;
+            Ok(output)
+        }
+    }
+
+    #[fixture]
+    fn fixture() -> Fixture {
+        let dir = TempDir::new().unwrap();
+        let identity = ::age::x25519::Identity::generate();
+        dir.child("identity.txt")
+            .write_str(&format!("{}\n", identity.to_string().expose_secret()))
+            .unwrap();
+        let ctx = MemoryContext::new(MemoryRepository::new(dir.path()));
+        let fixture = Fixture {
+            cmd: CommandContext { ctx },
+            dir,
+            identity,
         };
-        Ok(io::stdout().write_all(&result)?)
+        fixture.set_config(&format!(
+            "[config]\n\"secret.txt\" = [\"{}\"]\n",
+            fixture.identity.to_public()
+        ));
+        fixture
+    }
+
+    #[rstest]
+    fn test_clean_encrypts_new_file(fixture: Fixture) -> Result<()> {
+        let encrypted = fixture.clean(PLAINTEXT)?;
+
+        assert_eq!(fixture.decrypt(&encrypted)?, PLAINTEXT);
+        assert_eq!(
+            fixture.ctx().load_sidecar(&fixture.path(), "age")?,
+            Some(encrypted)
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_armored(fixture: Fixture) -> Result<()> {
+        fixture.set_config(&format!(
+            "version = 2\n\n[[rules]]\npaths = [\"secret.txt\"]\nrecipients = [\"{}\"]\narmor = true\n",
+            fixture.identity.to_public()
+        ));
+        let encrypted = fixture.clean(PLAINTEXT)?;
+
+        assert!(encrypted.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert_eq!(fixture.decrypt(&encrypted)?, PLAINTEXT);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::power_of_two("\"power-of-two\"", 64)]
+    #[case::bucket("{ bucket = 100 }", 100)]
+    #[case::size("{ size = 1024 }", 1024)]
+    fn test_clean_padded(
+        fixture: Fixture,
+        #[case] padding: &str,
+        #[case] size: usize,
+    ) -> Result<()> {
+        fixture.set_config(&format!(
+            "version = 2\n\n[[rules]]\npaths = [\"secret.txt\"]\nrecipients = [\"{}\"]\npadding = {}\n",
+            fixture.identity.to_public(),
+            padding
+        ));
+        fixture.add_identity()?;
+        let encrypted = fixture.clean(PLAINTEXT)?;
+
+        assert_eq!(fixture.decrypt(&encrypted)?.len(), size);
+        assert_eq!(fixture.smudge(&encrypted)?, PLAINTEXT);
+        // Files encrypted without padding still decrypt
+        assert_eq!(fixture.smudge(&fixture.encrypt(PLAINTEXT)?)?, PLAINTEXT);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_compressed(fixture: Fixture) -> Result<()> {
+        fixture.set_config(&format!(
+            "version = 2\n\n[[rules]]\npaths = [\"secret.txt\"]\nrecipients = [\"{}\"]\ncompress = \"zstd\"\n",
+            fixture.identity.to_public()
+        ));
+        fixture.add_identity()?;
+        let plaintext = PLAINTEXT.repeat(100);
+        let encrypted = fixture.clean(&plaintext)?;
+
+        assert!(fixture.decrypt(&encrypted)?.len() < plaintext.len());
+        assert_eq!(fixture.smudge(&encrypted)?, plaintext);
+        // Unchanged files aren't encrypted again
+        assert_eq!(fixture.clean(&plaintext)?, encrypted);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::group(Some("ops"), true)]
+    #[case::unset(Some("unset"), true)]
+    #[case::unknown_group(Some("dev"), false)]
+    #[case::no_group_name(Some("set"), false)]
+    fn test_clean_recipients_attribute(
+        fixture: Fixture,
+        #[case] groups: Option<&str>,
+        #[case] succeeds: bool,
+    ) -> Result<()> {
+        fixture.set_config(&format!(
+            "version = 2\ndefault = {{ recipients = [\"{}\"] }}\n\n[groups]\nops = [\"{}\"]\n",
+            fixture.identity.to_public(),
+            fixture.identity.to_public()
+        ));
+        if let Some(groups) = groups {
+            fixture
+                .ctx()
+                .repo()
+                .set_attribute("secret.txt", RECIPIENTS_ATTRIBUTE, groups);
+        }
+
+        match fixture.clean(PLAINTEXT) {
+            Ok(encrypted) => {
+                assert!(succeeds);
+                assert_eq!(fixture.decrypt(&encrypted)?, PLAINTEXT);
+            }
+            Err(_) => assert!(!succeeds),
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::own_identity(false, "", "refuse", true)]
+    #[case::foreign_warn(true, "", "warn", true)]
+    #[case::foreign_refuse(true, "", "refuse", false)]
+    #[case::foreign_escrow(true, "escrow = [\"{foreign}\"]\n", "refuse", true)]
+    fn test_clean_recipient_check(
+        fixture: Fixture,
+        #[case] foreign: bool,
+        #[case] escrow: &str,
+        #[case] check: &str,
+        #[case] succeeds: bool,
+    ) -> Result<()> {
+        fixture.add_identity()?;
+        // An identity whose recipient is unknown doesn't turn off the check
+        fixture.ctx().repo().add_config(
+            "git-agecrypt.config.identity",
+            fixture.dir.join("missing.txt").to_str().unwrap(),
+        )?;
+        fixture
+            .ctx()
+            .repo()
+            .set_config(RECIPIENT_CHECK_CONFIG, check)?;
+        let recipient = if foreign {
+            ::age::x25519::Identity::generate().to_public().to_string()
+        } else {
+            fixture.identity.to_public().to_string()
+        };
+        fixture.set_config(&format!(
+            "{}[config]\n\"secret.txt\" = [\"{recipient}\"]\n",
+            escrow.replace("{foreign}", &recipient)
+        ));
+
+        assert_eq!(fixture.clean(PLAINTEXT).is_ok(), succeeds);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_untrusted_recipient(fixture: Fixture) -> Result<()> {
+        fixture.clean(PLAINTEXT)?;
+
+        let foreign = ::age::x25519::Identity::generate().to_public();
+        fixture.ctx().set_config_contents(&format!(
+            "[config]\n\"secret.txt\" = [\"{}\", \"{foreign}\"]\n",
+            fixture.identity.to_public()
+        ));
+        let err = fixture.clean(b"changed").unwrap_err();
+        assert!(err.to_string().contains("aren't trusted"), "{err}");
+
+        // Only the exact key of the trust store counts
+        let repo = fixture.ctx().repo();
+        repo.add_config("git-agecrypt.config.untrusted", &foreign.to_string())?;
+        repo.add_config("foo.trusted", &foreign.to_string())?;
+        assert!(fixture.clean(b"changed").is_err());
+
+        fixture
+            .ctx()
+            .trusted_recipients()
+            .add(Recipient::new(foreign.to_string(), None))?;
+        fixture.clean(b"changed")?;
+
+        // Nothing is trusted in a fresh checkout, and filters don't trust anything on their own
+        let store = fixture.ctx().trusted_recipients();
+        for recipient in store.list()? {
+            fixture.ctx().trusted_recipients().remove(recipient)?;
+        }
+        let err = fixture.clean(b"fresh").unwrap_err();
+        assert!(err.to_string().contains("trust --all"), "{err}");
+        assert!(err
+            .to_string()
+            .contains(&age::long_fingerprint(&foreign.to_string())));
+        assert!(fixture.ctx().trusted_recipients().list()?.is_empty());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::signed(true, "refuse", true)]
+    #[case::unsigned_warn(false, "warn", true)]
+    #[case::unsigned_refuse(false, "refuse", false)]
+    fn test_signatures(
+        fixture: Fixture,
+        #[case] signed: bool,
+        #[case] check: &str,
+        #[case] succeeds: bool,
+    ) -> Result<()> {
+        let key = fixture.dir.join("signing_key");
+        duct::cmd!("ssh-keygen", "-q", "-t", "ed25519", "-N", "", "-f", &key).run()?;
+        let public_key = fs::read_to_string(key.with_extension("pub"))?;
+        fixture
+            .dir
+            .child("allowed_signers")
+            .write_str(&format!("alice@example.com {public_key}"))?;
+        fixture.set_config(&format!(
+            "version = 2\nallowed_signers = \"allowed_signers\"\n\n[[rules]]\npaths = [\"secret.txt\"]\nrecipients = [\"{}\"]\n",
+            fixture.identity.to_public()
+        ));
+        fixture.add_identity()?;
+        let repo = fixture.ctx().repo();
+        repo.set_config(SIGNATURE_CHECK_CONFIG, check)?;
+        if signed {
+            repo.set_config(SIGNING_KEY_CONFIG, key.to_str().unwrap())?;
+        }
+
+        let encrypted = fixture.clean(PLAINTEXT)?;
+        assert_eq!(sign::split(&encrypted).0.is_some(), signed);
+        fixture.ctx().remove_sidecar_files()?;
+        match fixture.smudge(&encrypted) {
+            Ok(decrypted) => {
+                assert!(succeeds);
+                assert_eq!(decrypted, PLAINTEXT);
+            }
+            Err(_) => assert!(!succeeds),
+        }
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_cache_hit(fixture: Fixture) -> Result<()> {
+        let encrypted = fixture.clean(PLAINTEXT)?;
+
+        // Encryption is not deterministic, a cache hit returns the same ciphertext
+        assert_eq!(fixture.clean(PLAINTEXT)?, encrypted);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_cache_miss_head_matches(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        let committed = fixture.encrypt(PLAINTEXT)?;
+        fixture.ctx().repo().commit("secret.txt", committed.clone());
+
+        assert_eq!(fixture.ctx().sidecar_count(), 0);
+        assert_eq!(fixture.clean(PLAINTEXT)?, committed);
+        assert_eq!(
+            fixture.ctx().load_sidecar(&fixture.path(), "age")?,
+            Some(committed)
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_cache_miss_index_matches(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        fixture
+            .ctx()
+            .repo()
+            .commit("secret.txt", fixture.encrypt(b"old contents")?);
+        let staged = fixture.encrypt(PLAINTEXT)?;
+        fixture.ctx().repo().stage("secret.txt", staged.clone());
+
+        assert_eq!(fixture.clean(PLAINTEXT)?, staged);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_cache_miss_head_matches_index_differs(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        let committed = fixture.encrypt(PLAINTEXT)?;
+        fixture.ctx().repo().commit("secret.txt", committed.clone());
+        fixture
+            .ctx()
+            .repo()
+            .stage("secret.txt", fixture.encrypt(b"staged contents")?);
+
+        assert_eq!(fixture.clean(PLAINTEXT)?, committed);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_staged_without_head(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        let staged = fixture.encrypt(PLAINTEXT)?;
+        fixture.ctx().repo().stage("secret.txt", staged.clone());
+
+        assert_eq!(fixture.clean(PLAINTEXT)?, staged);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_during_merge(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        let committed = fixture.encrypt(PLAINTEXT)?;
+        fixture.ctx().repo().commit("secret.txt", committed.clone());
+        fixture.ctx().repo().conflict(
+            "secret.txt",
+            fixture.encrypt(b"base contents")?,
+            committed.clone(),
+            fixture.encrypt(b"their contents")?,
+        );
+
+        assert_eq!(fixture.clean(PLAINTEXT)?, committed);
+
+        let merged = fixture.clean(b"merged contents")?;
+        assert_eq!(fixture.decrypt(&merged)?, b"merged contents");
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_reencrypts_changed_file(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        let committed = fixture.encrypt(b"old contents")?;
+        fixture.ctx().repo().commit("secret.txt", committed.clone());
+
+        let encrypted = fixture.clean(PLAINTEXT)?;
+        assert_ne!(encrypted, committed);
+        assert_eq!(fixture.decrypt(&encrypted)?, PLAINTEXT);
+
+        // The cache is updated to the new ciphertext
+        assert_eq!(fixture.clean(PLAINTEXT)?, encrypted);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_reencrypts_after_change_since_cached(fixture: Fixture) -> Result<()> {
+        let encrypted = fixture.clean(b"old contents")?;
+
+        let reencrypted = fixture.clean(PLAINTEXT)?;
+        assert_ne!(reencrypted, encrypted);
+        assert_eq!(fixture.decrypt(&reencrypted)?, PLAINTEXT);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_missing_identities(fixture: Fixture) -> Result<()> {
+        let committed = fixture.encrypt(PLAINTEXT)?;
+        fixture.ctx().repo().commit("secret.txt", committed);
+
+        assert!(fixture.clean(PLAINTEXT).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_missing_recipients(fixture: Fixture) -> Result<()> {
+        fixture.set_config("[config]\n");
+
+        assert!(fixture.clean(PLAINTEXT).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_smudge_populates_cache(fixture: Fixture) -> Result<()> {
+        fixture.add_identity()?;
+        let committed = fixture.encrypt(PLAINTEXT)?;
+
+        assert_eq!(fixture.smudge(&committed)?, PLAINTEXT);
+
+        // Cleaning the checked out file gives back the committed ciphertext
+        assert_eq!(fixture.clean(PLAINTEXT)?, committed);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_smudge_missing_identities(fixture: Fixture) -> Result<()> {
+        let committed = fixture.encrypt(PLAINTEXT)?;
+
+        let mut output = vec![];
+        let err = fixture
+            .cmd
+            .smudge(fixture.path(), &mut &committed[..], &mut output)
+            .unwrap_err();
+        let err: age::DecryptionError = err.downcast()?;
+        assert_eq!(err.path, Some(fixture.path()));
+        assert_eq!(err.stanza_types, vec!["X25519"]);
+        assert!(output.is_empty());
+        assert_eq!(fixture.ctx().sidecar_count(), 0);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::config(|f: &Fixture| {
+        f.ctx().repo().set_config(NO_KEY_CONFIG, "leave-encrypted").unwrap();
+    })]
+    #[case::attribute(|f: &Fixture| {
+        f.ctx().repo().set_attribute("secret.txt", NO_KEY_ATTRIBUTE, "leave-encrypted");
+    })]
+    #[case::rule(|f: &Fixture| {
+        f.set_config(&format!(
+            "[config]\n\"secret.txt\" = {{ recipients = [\"{}\"], no_key = \"leave-encrypted\" }}\n",
+            f.identity.to_public()
+        ));
+    })]
+    fn test_smudge_leaves_encrypted(
+        fixture: Fixture,
+        #[case] configure: fn(&Fixture),
+    ) -> Result<()> {
+        configure(&fixture);
+        let committed = fixture.encrypt(PLAINTEXT)?;
+
+        fixture.ctx().repo().commit("secret.txt", committed.clone());
+
+        assert_eq!(fixture.smudge(&committed)?, committed);
+        assert_eq!(fixture.ctx().sidecar_count(), 0);
+
+        // Cleaning the file left encrypted gives back the committed ciphertext
+        assert_eq!(fixture.clean(&committed)?, committed);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_clean_refuses_foreign_ciphertext(fixture: Fixture) -> Result<()> {
+        let other = ::age::x25519::Identity::generate().to_public().to_string();
+        fixture
+            .ctx()
+            .repo()
+            .commit("secret.txt", fixture.encrypt(PLAINTEXT)?);
+        let foreign = age::encrypt(&[other], &mut &PLAINTEXT[..])?;
+
+        let err = fixture.clean(&foreign).unwrap_err();
+        assert!(err.to_string().contains("already encrypted"), "{err}");
+        assert_eq!(fixture.ctx().sidecar_count(), 0);
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_smudge_rule_overrides_no_key_config(fixture: Fixture) -> Result<()> {
+        fixture
+            .ctx()
+            .repo()
+            .set_config(NO_KEY_CONFIG, "leave-encrypted")?;
+        fixture.set_config(&format!(
+            "[config]\n\"secret.txt\" = {{ recipients = [\"{}\"], no_key = \"fail\" }}\n",
+            fixture.identity.to_public()
+        ));
+        let committed = fixture.encrypt(PLAINTEXT)?;
+
+        assert!(fixture.smudge(&committed).is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::default(None)]
+    #[case::pass_through(Some("pass-through"))]
+    #[case::warn(Some("warn"))]
+    fn test_smudge_unencrypted_input(fixture: Fixture, #[case] policy: Option<&str>) -> Result<()> {
+        fixture.add_identity()?;
+        if let Some(policy) = policy {
+            fixture.ctx().repo().set_config(PLAINTEXT_CONFIG, policy)?;
+        }
+
+        assert_eq!(fixture.smudge(PLAINTEXT)?, PLAINTEXT);
+        assert_eq!(fixture.ctx().sidecar_count(), 0);
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::error("error")]
+    #[case::invalid("ignore")]
+    fn test_smudge_unencrypted_input_fails(fixture: Fixture, #[case] policy: &str) -> Result<()> {
+        fixture.add_identity()?;
+        fixture.ctx().repo().set_config(PLAINTEXT_CONFIG, policy)?;
+
+        assert!(fixture.smudge(PLAINTEXT).is_err());
+        Ok(())
     }
 }
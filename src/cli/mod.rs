@@ -2,5 +2,5 @@ mod app;
 mod args;
 mod internal;
 mod public;
-pub(crate) use app::run;
+pub(crate) use app::{run, run_standalone};
 pub(crate) use args::parse_args;
@@ -1,8 +1,16 @@
-use std::path::PathBuf;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
 
-use crate::{git, Result};
+use anyhow::bail;
 
-use crate::config::Validated;
+use crate::{age, frame, git, sign, Result};
+
+use super::internal::{self, describe, RECIPIENTS_ATTRIBUTE};
+
+use crate::config::{self, AppConfig, Recipient, Validated};
 use crate::git::Repository;
 use crate::{config::AgeIdentity, ctx::Context};
 
@@ -32,7 +40,7 @@ impl<C: Context> CommandContext<C> {
 
     pub(crate) fn deinit(&self) -> Result<()> {
         let repo = self.ctx.repo();
-        ensure_state(repo.remove_config_section("fiter.git-agecrypt"))?;
+        ensure_state(repo.remove_config_section("filter.git-agecrypt"))?;
         ensure_state(repo.remove_config_section("diff.git-agecrypt"))?;
 
         self.ctx.remove_sidecar_files()?;
@@ -47,13 +55,258 @@ impl<C: Context> CommandContext<C> {
         self.list_identities()?;
         println!();
         self.list_recipients()?;
+
+        let encrypted = self.encrypted_files()?;
+        if !encrypted.is_empty() {
+            println!();
+            println!("The following files are left encrypted in the working tree:");
+            for path in encrypted {
+                println!("    {}", path.display());
+            }
+        }
+
+        let fallback = self.fallback_files()?;
+        if !fallback.is_empty() {
+            println!();
+            if self.ctx.config()?.has_default() {
+                println!("The following files are encrypted to the default recipients:");
+            } else {
+                println!("The following protected files have no recipients configured:");
+            }
+            for path in fallback {
+                println!("    {}", path.display());
+            }
+        }
+
+        let plaintext = self.plaintext_files()?;
+        if !plaintext.is_empty() {
+            println!();
+            println!("The following files are committed unencrypted in HEAD:");
+            for path in plaintext {
+                println!("    {}", path.display());
+            }
+        }
         Ok(())
     }
 
-    pub(crate) fn add_identity(&self, identity: PathBuf) -> Result<()> {
+    /// Files in the index using the `git-agecrypt` filter without a rule or recipient groups
+    /// of their own, which are encrypted to the default recipients
+    fn fallback_files(&self) -> Result<Vec<PathBuf>> {
+        let cfg = self.ctx.config()?;
+        let configured = cfg.paths();
+        let repo = self.ctx.repo();
+        let mut rv = vec![];
+        for path in repo.index_paths()? {
+            if configured.contains(&path.as_path()) {
+                continue;
+            }
+            let abspath = repo.workdir().join(&path);
+            let filter = repo.get_attribute(&abspath, "filter")?;
+            let groups = repo.get_attribute(&abspath, RECIPIENTS_ATTRIBUTE)?;
+            let grouped = groups.is_some_and(|groups| groups != "unset");
+            if filter.as_deref() == Some("git-agecrypt") && !grouped {
+                rv.push(path);
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Files in the index which are encrypted, the ones with a rule and the ones using the
+    /// `git-agecrypt` filter whose recipients resolve, either to their recipient groups or to
+    /// the default recipients
+    fn protected_files(&self, cfg: &AppConfig) -> Result<Vec<PathBuf>> {
+        let configured = cfg.paths();
+        let repo = self.ctx.repo();
+        let mut rv = vec![];
+        for path in repo.index_paths()? {
+            let abspath = repo.workdir().join(&path);
+            if configured.contains(&path.as_path()) {
+                rv.push(path);
+                continue;
+            }
+            if repo.get_attribute(&abspath, "filter")?.as_deref() != Some("git-agecrypt") {
+                continue;
+            }
+            let groups = repo.get_attribute(&abspath, RECIPIENTS_ATTRIBUTE)?;
+            let groups = groups.as_deref().filter(|groups| *groups != "unset");
+            if cfg.get_recipients(&abspath, groups).is_ok() {
+                rv.push(path);
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Protected files whose blob in `HEAD` isn't encrypted, e.g. because they were committed
+    /// before being protected
+    fn plaintext_files(&self) -> Result<Vec<PathBuf>> {
+        let cfg = self.ctx.config()?;
+        let repo = self.ctx.repo();
+        let mut rv = vec![];
+        for path in self.protected_files(&cfg)? {
+            match repo.get_file_contents(&repo.workdir().join(&path)) {
+                Ok(contents) if !age::is_encrypted(sign::payload(&contents)) => rv.push(path),
+                Ok(_) => {}
+                Err(git::Error::NotExist(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(rv)
+    }
+
+    /// Protected files whose working tree copy is still encrypted, e.g. because no identity
+    /// could decrypt them on checkout
+    fn encrypted_files(&self) -> Result<Vec<PathBuf>> {
+        let cfg = self.ctx.config()?;
+        let mut rv = vec![];
+        for path in self.protected_files(&cfg)? {
+            match fs::read(self.ctx.repo().workdir().join(&path)) {
+                Ok(contents) if age::is_encrypted(sign::payload(&contents)) => rv.push(path),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(rv)
+    }
+
+    pub(crate) fn add_identity(&self, identity: PathBuf, refresh: bool) -> Result<()> {
         self.ctx
             .age_identities()
             .add(AgeIdentity::try_from(identity)?)?;
+        if refresh {
+            self.refresh_files(&[])?;
+        }
+        Ok(())
+    }
+
+    /// Decrypts the files left encrypted in the working tree, all of them if `paths` is empty
+    pub(crate) fn refresh(&self, paths: Vec<PathBuf>) -> Result<()> {
+        let failed = self.refresh_files(&paths)?;
+        if failed > 0 {
+            bail!("{} file(s) couldn't be decrypted", failed);
+        }
+        Ok(())
+    }
+
+    /// Decrypts protected files which are encrypted in the working tree, or missing from it
+    /// after a failed checkout, and stores their sidecar files so that git sees them unchanged.
+    /// Returns the number of files which still couldn't be decrypted.
+    fn refresh_files(&self, paths: &[PathBuf]) -> Result<usize> {
+        let cfg = self.ctx.config()?;
+        let protected = self.protected_files(&cfg)?;
+        let paths = paths
+            .iter()
+            .map(|path| cfg.repo_path(path))
+            .collect::<config::Result<Vec<_>>>()?;
+        if let Some(path) = paths.iter().find(|p| !protected.contains(p)) {
+            bail!("No configuration entry found for {}", path.display());
+        }
+
+        let repo = self.ctx.repo();
+        let mut files = vec![];
+        let mut failed = 0;
+        for path in &protected {
+            if !paths.is_empty() && !paths.contains(path) {
+                continue;
+            }
+            let file = repo.workdir().join(path);
+            let contents = match fs::read(&file) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    match repo.get_index_contents(&file) {
+                        Ok(contents) => contents,
+                        Err(git::Error::NotExist(_)) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !age::is_encrypted(sign::payload(&contents)) {
+                continue;
+            }
+            match internal::check_signature(&self.ctx, &cfg, path, &contents) {
+                Ok(()) => files.push((file, contents)),
+                Err(e) => {
+                    eprintln!("warning: {}", e);
+                    failed += 1;
+                }
+            }
+        }
+        if files.is_empty() {
+            return Ok(failed);
+        }
+
+        // Plugins are started once for all files
+        let session = self.ctx.age_session()?;
+        let encrypted: Vec<&[u8]> = files.iter().map(|(_, c)| sign::payload(c)).collect();
+        let mut decrypted_files = vec![];
+        for ((file, blob), decrypted) in files.iter().zip(session.decrypt_all(&encrypted)) {
+            let relpath = file.strip_prefix(repo.workdir()).unwrap_or(file);
+            let decrypted = decrypted.and_then(|d| d.map(frame::unwrap).transpose());
+            match decrypted.map_err(|e| age::DecryptionError::for_path(e, relpath)) {
+                Ok(Some(plaintext)) => {
+                    let hash = internal::plaintext_hash(&self.ctx, &plaintext)?;
+                    self.ctx.store_sidecar(file, "hash", hash.as_bytes())?;
+                    self.ctx.store_sidecar(file, "age", blob)?;
+                    replace_file(file, &plaintext)?;
+                    println!("Decrypted {}", relpath.display());
+                    decrypted_files.push(relpath);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("warning: {}", e);
+                    failed += 1;
+                }
+            }
+        }
+        // The clean filter reproduces the ciphertext from the sidecar files, so only the stat
+        // information in the index is refreshed, nothing else is staged
+        if !decrypted_files.is_empty() {
+            repo.refresh_index(&decrypted_files)?;
+        }
+        Ok(failed)
+    }
+
+    /// Checks the signatures of the protected files committed in `HEAD`, or of `paths`
+    pub(crate) fn verify(&self, paths: Vec<PathBuf>) -> Result<()> {
+        let cfg = self.ctx.config()?;
+        let Some(allowed_signers) = cfg.allowed_signers() else {
+            bail!("No allowed signers are set in git-agecrypt.toml");
+        };
+        let paths = if paths.is_empty() {
+            self.protected_files(&cfg)?
+        } else {
+            paths
+                .iter()
+                .map(|path| cfg.repo_path(path))
+                .collect::<config::Result<Vec<_>>>()?
+        };
+
+        let repo = self.ctx.repo();
+        let mut failed = 0;
+        for path in paths {
+            let blob = match repo.get_file_contents(&repo.workdir().join(&path)) {
+                Ok(blob) => blob,
+                Err(git::Error::NotExist(_)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let result = match sign::split(&blob) {
+                (Some(signature), ciphertext) => {
+                    sign::verify(&allowed_signers, signature, ciphertext)
+                }
+                (None, _) => Err(anyhow::anyhow!("not signed")),
+            };
+            match result {
+                Ok(principal) => println!("    ✓ {}: signed by {}", path.display(), principal),
+                Err(e) => {
+                    println!("    ⨯ {}: {:#}", path.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("{} file(s) don't have a valid signature", failed);
+        }
         Ok(())
     }
 
@@ -79,19 +332,98 @@ impl<C: Context> CommandContext<C> {
         Ok(())
     }
 
-    pub fn add_recipients(&self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
+    pub fn add_recipients(
+        &self,
+        recipients: Vec<String>,
+        label: Option<String>,
+        escrow: bool,
+        recipients_files: Vec<PathBuf>,
+        paths: Vec<PathBuf>,
+        allow_missing: bool,
+    ) -> Result<()> {
         let mut cfg = self.ctx.config()?;
 
-        cfg.add(recipients, paths)?;
+        if escrow {
+            cfg.add_escrow(&recipients);
+        }
+        self.trust_added(&recipients, &recipients_files)?;
+        cfg.add(recipients, label, recipients_files, paths, allow_missing)?;
+
+        self.ctx.save_config(&cfg)?;
+        Ok(())
+    }
+
+    pub fn add_default_recipients(
+        &self,
+        recipients: Vec<String>,
+        label: Option<String>,
+        escrow: bool,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut cfg = self.ctx.config()?;
+
+        if escrow {
+            cfg.add_escrow(&recipients);
+        }
+        self.trust_added(&recipients, &recipients_files)?;
+        cfg.add_default(recipients, label, recipients_files)?;
+
+        self.ctx.save_config(&cfg)?;
+        Ok(())
+    }
 
-        cfg.save()?;
+    pub fn add_group_recipients(
+        &self,
+        group: String,
+        recipients: Vec<String>,
+        label: Option<String>,
+        escrow: bool,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut cfg = self.ctx.config()?;
+
+        if escrow {
+            cfg.add_escrow(&recipients);
+        }
+        self.trust_added(&recipients, &recipients_files)?;
+        cfg.add_group(group, recipients, label, recipients_files)?;
+
+        self.ctx.save_config(&cfg)?;
         Ok(())
     }
 
-    pub fn remove_recipients(&self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
+    pub fn remove_group_recipients(
+        &self,
+        group: String,
+        recipients: Vec<String>,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
         let mut cfg = self.ctx.config()?;
-        cfg.remove(recipients, paths)?;
-        cfg.save()?;
+        cfg.remove_group(&group, recipients, recipients_files)?;
+        self.ctx.save_config(&cfg)?;
+        Ok(())
+    }
+
+    pub fn remove_default_recipients(
+        &self,
+        recipients: Vec<String>,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut cfg = self.ctx.config()?;
+        cfg.remove_default(recipients, recipients_files)?;
+        self.ctx.save_config(&cfg)?;
+        Ok(())
+    }
+
+    pub fn remove_recipients(
+        &self,
+        recipients: Vec<String>,
+        recipients_files: Vec<PathBuf>,
+        paths: Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut cfg = self.ctx.config()?;
+        cfg.remove(recipients, recipients_files, paths)?;
+        self.ctx.save_config(&cfg)?;
         Ok(())
     }
 
@@ -105,7 +437,136 @@ impl<C: Context> CommandContext<C> {
         }
         Ok(())
     }
+
+    /// Trusts recipients added from the command line, as they are approved by whoever adds them
+    fn trust_added(&self, recipients: &[String], recipients_files: &[PathBuf]) -> Result<()> {
+        let mut keys = recipients.to_vec();
+        for file in recipients_files {
+            keys.extend(age::read_recipients_file(
+                self.ctx.repo().workdir().join(file),
+            )?);
+        }
+        let mut store = self.ctx.trusted_recipients();
+        for key in keys {
+            match store.add(Recipient::new(key, None)) {
+                Ok(()) | Err(config::Error::AlreadyExists(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Approves `recipients`, given by their key, label or fingerprint, or every
+    /// recipient of the configuration with `all`; lists them if none are given
+    pub(crate) fn trust(&self, recipients: Vec<String>, all: bool) -> Result<()> {
+        let cfg = self.ctx.config()?;
+        let configured = cfg.recipients()?;
+        let mut store = self.ctx.trusted_recipients();
+        let trusted = store.list()?;
+        let is_trusted = |r: &Recipient| trusted.iter().any(|t| t.same_key(r));
+
+        if recipients.is_empty() && !all {
+            // Trusted keys are labelled by the configuration, if they are still in it
+            let trusted: Vec<&Recipient> = trusted
+                .iter()
+                .map(|t| configured.iter().find(|r| r.same_key(t)).unwrap_or(t))
+                .collect();
+            if trusted.is_empty() {
+                println!(
+                    "No recipients are trusted yet, approve them with `git-agecrypt trust --all`"
+                );
+            } else {
+                println!("The following recipients are trusted:");
+            }
+            for r in trusted {
+                println!("    ✓ {}", describe(r));
+            }
+            let untrusted: Vec<&Recipient> = configured.iter().filter(|r| !is_trusted(r)).collect();
+            if !untrusted.is_empty() {
+                println!();
+                println!("The following recipients of git-agecrypt.toml aren't trusted:");
+                for r in untrusted {
+                    println!("    ⨯ {}", describe(r));
+                }
+            }
+            return Ok(());
+        }
+
+        let approved: Vec<Recipient> = if all {
+            configured
+                .iter()
+                .filter(|r| !is_trusted(r))
+                .cloned()
+                .collect()
+        } else {
+            let candidates: Vec<&Recipient> = configured.iter().collect();
+            let mut approved = vec![];
+            for query in recipients {
+                match config::find_recipients(std::slice::from_ref(&query), &candidates) {
+                    Ok(found) => approved.extend(found.into_iter().cloned()),
+                    // Keys can be trusted before they are added to the configuration
+                    Err(config::Error::NotExist(_))
+                        if age::validate_public_keys(&[&query]).is_ok() =>
+                    {
+                        approved.push(Recipient::new(query, None))
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            approved
+        };
+        for r in approved {
+            match store.add(r.clone()) {
+                Ok(()) => println!("Trusted {}", describe(&r)),
+                Err(config::Error::AlreadyExists(_)) => println!("{} is already trusted", r),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Revokes the approval of `recipients`, given by their key, label or fingerprint
+    pub(crate) fn untrust(&self, recipients: Vec<String>) -> Result<()> {
+        let cfg = self.ctx.config()?;
+        let configured = cfg.recipients()?;
+        let mut store = self.ctx.trusted_recipients();
+        let trusted: Vec<Recipient> = store
+            .list()?
+            .into_iter()
+            .map(|t| {
+                configured
+                    .iter()
+                    .find(|r| r.same_key(&t))
+                    .cloned()
+                    .unwrap_or(t)
+            })
+            .collect();
+        let candidates: Vec<&Recipient> = trusted.iter().collect();
+        let revoked = config::find_recipients(&recipients, &candidates)?;
+        for r in revoked {
+            store.remove(r.clone())?;
+            println!("Untrusted {}", r);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn migrate_config(&self) -> Result<()> {
+        let mut cfg = self.ctx.config()?;
+        match cfg.migrate() {
+            Some(version) => {
+                self.ctx.save_config(&cfg)?;
+                println!(
+                    "Migrated git-agecrypt.toml from version {} to {}",
+                    version,
+                    cfg.version()
+                );
+            }
+            None => println!("git-agecrypt.toml is already at version {}", cfg.version()),
+        }
+        Ok(())
+    }
 }
+
 fn ensure_state(result: git::Result<()>) -> Result<()> {
     match result {
         Ok(()) => Ok(()),
@@ -116,3 +577,294 @@ fn ensure_state(result: git::Result<()>) -> Result<()> {
         },
     }
 }
+
+/// Replaces `file` with `contents` at once, so that it isn't left truncated if writing fails,
+/// keeping the permissions of the file it replaces
+fn replace_file(file: &Path, contents: &[u8]) -> io::Result<()> {
+    let permissions = match fs::metadata(file) {
+        Ok(metadata) => metadata.permissions(),
+        // Restored from the index, there is nothing to lose
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return fs::write(file, contents),
+        Err(e) => return Err(e),
+    };
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(contents)?;
+    tmp.as_file().set_permissions(permissions)?;
+    tmp.persist(file).map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use ::age::secrecy::ExposeSecret;
+    use anyhow::Result;
+    use assert_fs::{prelude::*, TempDir};
+    use assert_matches::assert_matches;
+
+    use super::*;
+    use crate::{ctx::MemoryContext, git::MemoryRepository};
+
+    fn command_context() -> CommandContext<MemoryContext> {
+        let ctx = MemoryContext::new(MemoryRepository::new("/repo"));
+        CommandContext::new(ctx)
+    }
+
+    #[test]
+    fn test_init_deinit() -> Result<()> {
+        let cmd = command_context();
+        let repo = cmd.ctx.repo();
+
+        cmd.init()?;
+        assert_eq!(repo.get_config("filter.git-agecrypt.required")?, "true");
+        assert_eq!(
+            repo.get_config("filter.git-agecrypt.clean")?,
+            "git-agecrypt clean -f %f"
+        );
+        assert_eq!(
+            repo.get_config("diff.git-agecrypt.textconv")?,
+            "git-agecrypt textconv"
+        );
+
+        // Running it again is harmless
+        cmd.init()?;
+
+        let file = Path::new("/repo/secret.txt");
+        cmd.ctx.store_sidecar(file, "hash", b"hash")?;
+
+        cmd.deinit()?;
+        assert_eq!(repo.list_config("git-agecrypt")?, [] as [String; 0]);
+        assert_eq!(cmd.ctx.load_sidecar(file, "hash")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_config() -> Result<()> {
+        let cmd = command_context();
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        cmd.ctx.set_config_contents(&format!(
+            "[config]\n\"a.txt\" = [\"{recipient}\"]\n\"b.txt\" = [\"{recipient}\"]\n"
+        ));
+
+        cmd.migrate_config()?;
+        let cfg = cmd.ctx.config()?;
+        assert_eq!(cfg.version(), 2);
+        assert!(cfg.paths() == vec![Path::new("a.txt"), Path::new("b.txt")]);
+
+        // Running it again is harmless
+        cmd.migrate_config()?;
+        assert_eq!(cmd.ctx.config()?.to_toml()?, cfg.to_toml()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trust_untrust() -> Result<()> {
+        let cmd = command_context();
+        let alice = ::age::x25519::Identity::generate().to_public().to_string();
+        let bob = ::age::x25519::Identity::generate().to_public().to_string();
+        let trusted = |cmd: &CommandContext<MemoryContext>| -> Result<Vec<String>> {
+            let trusted = cmd.ctx.trusted_recipients().list()?;
+            Ok(trusted.iter().map(|r| r.key().to_string()).collect())
+        };
+        cmd.ctx.set_config_contents(&format!(
+            "version = 2\n[[rules]]\npaths = [\"a.txt\"]\nrecipients = [{{ key = \"{alice}\", label = \"alice\" }}]\n"
+        ));
+
+        // Only recipients added from the command line are trusted, the existing ones have to be
+        // approved explicitly
+        cmd.add_default_recipients(vec![bob.clone()], None, false, vec![])?;
+        assert_eq!(trusted(&cmd)?, vec![bob.clone()]);
+
+        cmd.trust(vec![], true)?;
+        assert_eq!(trusted(&cmd)?, vec![bob.clone(), alice.clone()]);
+        cmd.untrust(vec!["alice".into()])?;
+        assert_eq!(trusted(&cmd)?, vec![bob.clone()]);
+        assert_matches!(cmd.untrust(vec!["alice".into()]), Err(_));
+        cmd.untrust(vec![bob.clone()])?;
+        assert!(trusted(&cmd)?.is_empty());
+
+        cmd.trust(vec![age::long_fingerprint(&alice)], false)?;
+        cmd.trust(vec![age::fingerprint(&bob)], false)?;
+        assert_eq!(trusted(&cmd)?, vec![alice.clone(), bob.clone()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = age::encrypt(&[&recipient], &mut &b"secret"[..])?;
+        let cmd = CommandContext::new(MemoryContext::new(MemoryRepository::new(dir.path())));
+        let repo = cmd.ctx.repo();
+        for (path, contents) in [
+            ("encrypted.txt", &encrypted[..]),
+            ("decrypted.txt", b"secret"),
+            ("fallback.txt", &encrypted),
+            ("public.txt", &encrypted),
+        ] {
+            dir.child(path).write_binary(contents)?;
+            repo.stage(path, encrypted.clone());
+        }
+        repo.stage("missing.txt", encrypted.clone());
+        repo.set_attribute("fallback.txt", "filter", "git-agecrypt");
+        cmd.ctx.set_config_contents(&format!(
+            "default = [\"{recipient}\"]\n[config]\n\"encrypted.txt\" = [\"{recipient}\"]\n\"decrypted.txt\" = [\"{recipient}\"]\n\"missing.txt\" = [\"{recipient}\"]\n"
+        ));
+
+        assert_eq!(
+            cmd.encrypted_files()?,
+            vec![
+                PathBuf::from("encrypted.txt"),
+                PathBuf::from("fallback.txt")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_grouped_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = age::encrypt(&[&recipient], &mut &b"secret"[..])?;
+        let cmd = CommandContext::new(MemoryContext::new(MemoryRepository::new(dir.path())));
+        let repo = cmd.ctx.repo();
+        for (path, groups) in [
+            ("grouped.txt", "ops"),
+            ("unknown.txt", "dev"),
+            ("ungrouped.txt", "unset"),
+        ] {
+            dir.child(path).write_binary(&encrypted)?;
+            repo.stage(path, encrypted.clone());
+            repo.set_attribute(path, "filter", "git-agecrypt");
+            repo.set_attribute(path, RECIPIENTS_ATTRIBUTE, groups);
+        }
+        cmd.ctx.set_config_contents(&format!(
+            "version = 2\n\n[groups]\nops = [\"{recipient}\"]\n"
+        ));
+
+        // Files of unknown groups or without any recipients aren't encrypted
+        assert_eq!(cmd.encrypted_files()?, vec![PathBuf::from("grouped.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plaintext_files() -> Result<()> {
+        let cmd = command_context();
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = age::encrypt(&[&recipient], &mut &b"secret"[..])?;
+        cmd.ctx.repo().commit("encrypted.txt", encrypted);
+        cmd.ctx.repo().commit("plaintext.txt", "secret");
+        cmd.ctx.repo().commit("unprotected.txt", "public");
+        cmd.ctx.repo().commit("fallback.txt", "secret");
+        cmd.ctx
+            .repo()
+            .set_attribute("fallback.txt", "filter", "git-agecrypt");
+        cmd.ctx.set_config_contents(&format!(
+            "default = [\"{recipient}\"]\n[config]\n\"encrypted.txt\" = [\"{recipient}\"]\n\"plaintext.txt\" = [\"{recipient}\"]\n\"new.txt\" = [\"{recipient}\"]\n"
+        ));
+
+        assert_eq!(
+            cmd.plaintext_files()?,
+            vec![
+                PathBuf::from("fallback.txt"),
+                PathBuf::from("plaintext.txt")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fallback_files() -> Result<()> {
+        let cmd = command_context();
+        let repo = cmd.ctx.repo();
+        let recipient = ::age::x25519::Identity::generate().to_public().to_string();
+        for path in [
+            "configured.txt",
+            "grouped.txt",
+            "fallback.txt",
+            "public.txt",
+        ] {
+            repo.stage(path, "contents");
+        }
+        repo.set_attribute("configured.txt", "filter", "git-agecrypt");
+        repo.set_attribute("grouped.txt", "filter", "git-agecrypt");
+        repo.set_attribute("grouped.txt", RECIPIENTS_ATTRIBUTE, "ops");
+        repo.set_attribute("fallback.txt", "filter", "git-agecrypt");
+        cmd.ctx.set_config_contents(&format!(
+            "default = [\"{recipient}\"]\n[config]\n\"configured.txt\" = [\"{recipient}\"]\n"
+        ));
+
+        assert_eq!(cmd.fallback_files()?, vec![PathBuf::from("fallback.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_refresh() -> Result<()> {
+        let dir = TempDir::new()?;
+        let identity = ::age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let other = ::age::x25519::Identity::generate().to_public().to_string();
+        let encrypted = age::encrypt(&[&recipient], &mut &b"secret"[..])?;
+        let missing = age::encrypt(&[&recipient], &mut &b"missing"[..])?;
+        let foreign = age::encrypt(&[&other], &mut &b"foreign"[..])?;
+        let fallback = age::encrypt(&[&recipient], &mut &b"fallback"[..])?;
+        dir.child("identity.txt")
+            .write_str(&format!("{}\n", identity.to_string().expose_secret()))?;
+        let cmd = CommandContext::new(MemoryContext::new(MemoryRepository::new(dir.path())));
+        let repo = cmd.ctx.repo();
+        for (path, contents) in [
+            ("encrypted.txt", &encrypted),
+            ("foreign.txt", &foreign),
+            ("fallback.txt", &fallback),
+        ] {
+            dir.child(path).write_binary(contents)?;
+            repo.stage(path, contents.clone());
+        }
+        repo.stage("missing.txt", missing.clone());
+        repo.set_attribute("fallback.txt", "filter", "git-agecrypt");
+        cmd.ctx.set_config_contents(&format!(
+            "default = [\"{recipient}\"]\n[config]\n\"encrypted.txt\" = [\"{recipient}\"]\n\"missing.txt\" = [\"{recipient}\"]\n\"foreign.txt\" = [\"{other}\"]\n"
+        ));
+
+        // Nothing can be decrypted before the identity is added
+        cmd.refresh(vec!["encrypted.txt".into()]).unwrap_err();
+        assert_eq!(fs::read(dir.join("encrypted.txt"))?, encrypted);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.join("encrypted.txt"), fs::Permissions::from_mode(0o750))?;
+        }
+
+        cmd.add_identity(dir.join("identity.txt"), true)?;
+        dir.child("encrypted.txt").assert("secret");
+        // Replaced along with its permissions
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join("encrypted.txt"))?
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o750);
+        }
+        dir.child("missing.txt").assert("missing");
+        dir.child("fallback.txt").assert("fallback");
+        assert_eq!(fs::read(dir.join("foreign.txt"))?, foreign);
+        let file = dir.join("encrypted.txt");
+        assert_eq!(cmd.ctx.load_sidecar(&file, "age")?, Some(encrypted));
+        let hash = internal::plaintext_hash(&cmd.ctx, b"secret")?;
+        assert_eq!(
+            cmd.ctx.load_sidecar(&file, "hash")?,
+            Some(hash.as_bytes().to_vec())
+        );
+
+        assert_matches!(
+            cmd.refresh(vec![]),
+            Err(e) if e.to_string() == "1 file(s) couldn't be decrypted"
+        );
+        assert_matches!(cmd.refresh(vec!["unknown.txt".into()]), Err(_));
+        Ok(())
+    }
+}
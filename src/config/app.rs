@@ -1,44 +1,354 @@
 use std::{
-    collections::HashMap,
-    fs, io,
-    path::{Path, PathBuf},
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
 };
 
 use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
+use toml_edit::DocumentMut;
 
 use crate::age;
 
-use super::Result;
+use super::{
+    schema::{Contents, CURRENT_VERSION},
+    Error, Result,
+};
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
-pub struct RecipientEntry {
-    paths: Vec<PathBuf>,
-    recipients: Vec<String>,
+/// A recipient with an optional human readable label, e.g. a name or an email address
+///
+/// Serialized as a plain string unless a label is set. SSH keys without an explicit label are
+/// labelled with the comment of the key.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "RecipientRepr", into = "RecipientRepr")]
+pub struct Recipient {
+    key: String,
+    label: Option<String>,
+}
+
+impl Recipient {
+    pub fn new(key: String, label: Option<String>) -> Self {
+        Self { key, label }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label
+            .as_deref()
+            .or_else(|| age::ssh_comment(&self.key))
+    }
+
+    pub fn fingerprint(&self) -> String {
+        age::fingerprint(&self.key)
+    }
+
+    /// Fingerprint long enough to verify the key out of band
+    pub fn long_fingerprint(&self) -> String {
+        age::long_fingerprint(&self.key)
+    }
+
+    /// Whether both are the same key, regardless of their labels and SSH key comments
+    pub fn same_key(&self, other: &Recipient) -> bool {
+        age::recipient_key(&self.key) == age::recipient_key(&other.key)
+    }
+
+    /// Whether `query` is the key, the label or the short or long fingerprint of this recipient
+    fn matches(&self, query: &str) -> bool {
+        self.key == query
+            || self.label() == Some(query)
+            || self.fingerprint() == query
+            || self.long_fingerprint() == query
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.label() {
+            Some(label) => write!(f, "{} ({})", label, self.fingerprint()),
+            None => write!(f, "{} ({})", self.key, self.fingerprint()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RecipientRepr {
+    Key(String),
+    Labeled(LabeledRecipient),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LabeledRecipient {
+    key: String,
+    label: String,
+}
+
+impl From<RecipientRepr> for Recipient {
+    fn from(value: RecipientRepr) -> Self {
+        match value {
+            RecipientRepr::Key(key) => Self { key, label: None },
+            RecipientRepr::Labeled(LabeledRecipient { key, label }) => Self {
+                key,
+                label: Some(label),
+            },
+        }
+    }
+}
+
+impl From<Recipient> for RecipientRepr {
+    fn from(value: Recipient) -> Self {
+        match value.label {
+            Some(label) => Self::Labeled(LabeledRecipient {
+                key: value.key,
+                label,
+            }),
+            None => Self::Key(value.key),
+        }
+    }
+}
+
+/// What happens on checkout when none of the identities can decrypt a file
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NoKeyPolicy {
+    /// Abort the checkout
+    #[default]
+    Fail,
+    /// Leave the ciphertext in the working tree with a warning
+    LeaveEncrypted,
+}
+
+impl FromStr for NoKeyPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "leave-encrypted" => Ok(Self::LeaveEncrypted),
+            _ => Err(anyhow!(
+                "Invalid no-key policy '{}', expected 'fail' or 'leave-encrypted'",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens on checkout when a protected file is committed unencrypted, e.g. before its
+/// `.gitattributes` entry existed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlaintextPolicy {
+    /// Check out the file as it is
+    PassThrough,
+    /// Check out the file as it is with a warning
+    #[default]
+    Warn,
+    /// Abort the checkout
+    Error,
+}
+
+impl FromStr for PlaintextPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pass-through" => Ok(Self::PassThrough),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(anyhow!(
+                "Invalid plaintext policy '{}', expected 'pass-through', 'warn' or 'error'",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens on commit when none of the local identities or escrow recipients can decrypt
+/// a file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecipientCheck {
+    /// Encrypt the file with a warning
+    #[default]
+    Warn,
+    /// Abort the commit
+    Refuse,
+}
+
+impl FromStr for RecipientCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "refuse" => Ok(Self::Refuse),
+            _ => Err(anyhow!(
+                "Invalid recipient check '{}', expected 'warn' or 'refuse'",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens on checkout when a ciphertext isn't signed by an allowed signer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureCheck {
+    /// Decrypt the file with a warning
+    #[default]
+    Warn,
+    /// Abort the checkout
+    Refuse,
+}
+
+impl FromStr for SignatureCheck {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "refuse" => Ok(Self::Refuse),
+            _ => Err(anyhow!(
+                "Invalid signature check '{}', expected 'warn' or 'refuse'",
+                s
+            )),
+        }
+    }
+}
+
+/// How files are compressed before encryption
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    Zstd,
+}
+
+/// How encrypted files are padded to hide the length of their contents
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Padding {
+    /// Pad to the next power of two
+    PowerOfTwo,
+    /// Pad to a multiple of the given number of bytes
+    Bucket(u64),
+    /// Pad to exactly the given number of bytes, larger files can't be encrypted
+    Size(u64),
+}
+
+/// Where the sidecar files caching the ciphertexts of the working tree are kept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheLocation {
+    /// Inside the `.git` directory
+    #[default]
+    Git,
+    /// Under `$XDG_CACHE_HOME`, e.g. to keep them out of backups of the repository
+    Xdg,
+}
+
+impl FromStr for CacheLocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "git" => Ok(Self::Git),
+            "xdg" => Ok(Self::Xdg),
+            _ => Err(anyhow!(
+                "Invalid cache location '{}', expected 'git' or 'xdg'",
+                s
+            )),
+        }
+    }
+}
+
+/// Recipients and options of the paths listed in a rule
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Rule {
+    /// Paths relative to the root of the repository, empty for the default rule
+    pub(super) paths: Vec<PathBuf>,
+    pub(super) recipients: Vec<Recipient>,
+    pub(super) recipients_file: Vec<PathBuf>,
+    pub(super) no_key: Option<NoKeyPolicy>,
+    /// Whether files are encrypted in the ASCII armored format
+    pub(super) armor: bool,
+    pub(super) compress: Option<Compression>,
+    pub(super) padding: Option<Padding>,
+}
+
+impl Rule {
+    fn is_empty(&self) -> bool {
+        self.recipients.is_empty() && self.recipients_file.is_empty()
+    }
+
+    /// Whether both rules encrypt the same way, regardless of their paths
+    fn same_settings(&self, other: &Rule) -> bool {
+        self.recipients == other.recipients
+            && self.recipients_file == other.recipients_file
+            && self.no_key == other.no_key
+            && self.armor == other.armor
+            && self.compress == other.compress
+            && self.padding == other.padding
+    }
+
+    /// Adds `recipients` and `recipients_files`, relabelling existing recipients if `label` is
+    /// given
+    fn add(&mut self, recipients: &[String], label: &Option<String>, recipients_files: &[PathBuf]) {
+        for key in recipients {
+            let recipient = Recipient::new(key.clone(), label.clone());
+            match self.recipients.iter_mut().find(|r| r.same_key(&recipient)) {
+                Some(existing) if label.is_some() => *existing = recipient,
+                Some(_) => {}
+                None => self.recipients.push(recipient),
+            }
+        }
+        for file in recipients_files {
+            if !self.recipients_file.contains(file) {
+                self.recipients_file.push(file.clone());
+            }
+        }
+    }
+
+    /// Removes the recipients matching `removed` and `recipients_files`
+    fn remove(&mut self, removed: impl Fn(&Recipient) -> bool, recipients_files: &[PathBuf]) {
+        self.recipients.retain(|r| !removed(r));
+        self.recipients_file
+            .retain(|f| !recipients_files.contains(f));
+    }
+}
+
 pub struct AppConfig {
-    config: HashMap<PathBuf, Vec<String>>,
-    #[serde(skip)]
+    /// Version of the format of the configuration file, kept when saving
+    version: u32,
+    /// Recipients held in escrow, e.g. an offline recovery key, which count as being able to
+    /// decrypt the files encrypted to them
+    escrow: Vec<Recipient>,
+    /// Rule of the protected paths without a rule of their own
+    default: Option<Rule>,
+    /// Named sets of recipients, assigned to paths by the `agecrypt-recipients` attribute
+    groups: BTreeMap<String, Rule>,
+    /// Rules in order of precedence, the first rule listing a path applies to it
+    rules: Vec<Rule>,
+    /// File of the SSH keys allowed to sign ciphertexts, relative to the root of the repository
+    allowed_signers: Option<PathBuf>,
+    /// The configuration file as it was loaded, updated in place when saving
+    document: DocumentMut,
     path: PathBuf,
-    #[serde(skip)]
     prefix: PathBuf,
 }
 
 impl AppConfig {
     pub fn load(path: &Path, repo_prefix: &Path) -> Result<Self> {
         match fs::read_to_string(path) {
-            Ok(contents) => {
-                let mut cfg: AppConfig = toml::from_str(&contents).with_context(|| {
-                    format!("Couldn't load configuration file '{}'", path.display())
-                })?;
-                cfg.path = path.into();
-                cfg.prefix = repo_prefix.into();
-                Ok(cfg)
-            }
+            Ok(contents) => Self::parse(&contents, path, repo_prefix),
             Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self {
-                config: HashMap::new(),
+                version: CURRENT_VERSION,
+                escrow: vec![],
+                default: None,
+                groups: BTreeMap::new(),
+                rules: vec![],
+                allowed_signers: None,
+                document: DocumentMut::new(),
                 path: path.into(),
                 prefix: repo_prefix.into(),
             }),
@@ -48,79 +358,1367 @@ impl AppConfig {
         }
     }
 
+    pub fn parse(contents: &str, path: &Path, repo_prefix: &Path) -> Result<Self> {
+        let Contents {
+            version,
+            escrow,
+            default,
+            groups,
+            rules,
+            allowed_signers,
+        } = Contents::parse(contents)
+            .with_context(|| format!("Couldn't load configuration file '{}'", path.display()))?;
+        let rules = rules
+            .into_iter()
+            .map(|rule| Rule {
+                // Written by hand or by earlier releases as they were typed, e.g. `./secret`
+                paths: rule
+                    .paths
+                    .iter()
+                    .map(|p| normalize(p).unwrap_or_else(|| p.clone()))
+                    .collect(),
+                ..rule
+            })
+            .collect();
+        let document = contents
+            .parse()
+            .with_context(|| format!("Couldn't load configuration file '{}'", path.display()))?;
+        Ok(Self {
+            version,
+            escrow,
+            default,
+            groups,
+            rules,
+            allowed_signers,
+            document,
+            path: path.into(),
+            prefix: repo_prefix.into(),
+        })
+    }
+
     pub fn save(&self) -> Result<()> {
-        let cfg = toml::to_string_pretty(self).context("Coldn't format configuration as TOML")?;
-        fs::write(&self.path, cfg).with_context(|| {
+        fs::write(&self.path, self.to_toml()?).with_context(|| {
             format!("Couldn't save configuration file '{}'", self.path.display())
         })?;
         Ok(())
     }
 
-    pub fn add(&mut self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
-        age::validate_public_keys(&recipients)?;
-        let invalid_paths: Vec<String> = paths
-            .iter()
-            .filter(|&p| !p.is_file())
-            .map(|f| f.to_string_lossy().to_string())
-            .collect();
-        if !invalid_paths.is_empty() {
+    pub fn to_toml(&self) -> Result<String> {
+        let contents = Contents {
+            version: self.version,
+            escrow: self.escrow.clone(),
+            default: self.default.clone(),
+            groups: self.groups.clone(),
+            rules: self.rules.clone(),
+            allowed_signers: self.allowed_signers.clone(),
+        };
+        let mut document = self.document.clone();
+        contents.update(&mut document)?;
+        Ok(document.to_string())
+    }
+
+    /// Version of the format of the configuration file
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Converts the configuration to the newest format, returning the version it was in if it
+    /// had to be converted
+    ///
+    /// Paths with the same recipients and options are merged into a single rule.
+    pub fn migrate(&mut self) -> Option<u32> {
+        if self.version == CURRENT_VERSION {
+            return None;
+        }
+        let mut rules: Vec<Rule> = vec![];
+        for rule in self.rules.drain(..) {
+            match rules.iter_mut().find(|r| r.same_settings(&rule)) {
+                Some(existing) => existing.paths.extend(rule.paths),
+                None => rules.push(rule),
+            }
+        }
+        self.rules = rules;
+        Some(std::mem::replace(&mut self.version, CURRENT_VERSION))
+    }
+
+    pub fn add(
+        &mut self,
+        recipients: Vec<String>,
+        label: Option<String>,
+        recipients_files: Vec<PathBuf>,
+        paths: Vec<PathBuf>,
+        allow_missing: bool,
+    ) -> Result<()> {
+        let recipients_files = self.repo_paths(&recipients_files)?;
+        self.validate(&recipients, &label, &recipients_files)?;
+        let paths = self.repo_paths(&paths)?;
+        let mut missing = vec![];
+        for path in &paths {
+            let file = self.prefix.join(path);
+            if file.is_dir() {
+                return Err(anyhow!(
+                    "'{}' is a directory, only files can be encrypted",
+                    path.display()
+                )
+                .into());
+            }
+            if !file.is_file() {
+                missing.push(path.to_string_lossy().to_string());
+            }
+        }
+        if !missing.is_empty() && !allow_missing {
             return Err(anyhow!(
-                "The follwing files doesn't exist: {}",
-                invalid_paths.join(", ")
+                "The following files don't exist: {}, use --allow-missing to protect them before \
+                 they are created",
+                missing.join(", ")
             )
             .into());
         }
-        for path in paths {
-            let entry = self.config.entry(path).or_default();
-            entry.extend(recipients.clone().into_iter());
-            entry.dedup();
+        let mut new_paths = vec![];
+        for path in &paths {
+            match self.own_rule(path, &paths) {
+                Some(i) => self.rules[i].add(&recipients, &label, &recipients_files),
+                None if !new_paths.contains(path) => new_paths.push(path.clone()),
+                None => {}
+            }
+        }
+        if !new_paths.is_empty() {
+            let mut rule = Rule {
+                paths: new_paths,
+                ..Default::default()
+            };
+            rule.add(&recipients, &label, &recipients_files);
+            self.rules.push(rule);
         }
         Ok(())
     }
 
-    pub fn remove(&mut self, recipients: Vec<String>, paths: Vec<PathBuf>) -> Result<()> {
+    /// Adds recipients to the default rule, used by protected paths without a rule
+    pub fn add_default(
+        &mut self,
+        recipients: Vec<String>,
+        label: Option<String>,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        let recipients_files = self.repo_paths(&recipients_files)?;
+        self.validate(&recipients, &label, &recipients_files)?;
+        self.default
+            .get_or_insert_with(Rule::default)
+            .add(&recipients, &label, &recipients_files);
+        Ok(())
+    }
+
+    /// Adds recipients to the group `name`, creating it if needed
+    pub fn add_group(
+        &mut self,
+        name: String,
+        recipients: Vec<String>,
+        label: Option<String>,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        if name.is_empty() || name.contains(|c: char| c == ',' || c.is_whitespace()) {
+            return Err(anyhow!(
+                "Invalid group name '{}', it can't contain commas or whitespace",
+                name
+            )
+            .into());
+        }
+        let recipients_files = self.repo_paths(&recipients_files)?;
+        self.validate(&recipients, &label, &recipients_files)?;
+        self.groups
+            .entry(name)
+            .or_default()
+            .add(&recipients, &label, &recipients_files);
+        Ok(())
+    }
+
+    fn validate(
+        &self,
+        recipients: &[String],
+        label: &Option<String>,
+        recipients_files: &[PathBuf],
+    ) -> Result<()> {
+        if label.is_some() && recipients.len() != 1 {
+            return Err(anyhow!("A label can only be given for a single recipient").into());
+        }
+        age::validate_public_keys(recipients)?;
+        for file in recipients_files {
+            age::read_recipients_file(self.prefix.join(file))?;
+        }
+        Ok(())
+    }
+
+    /// Converts `path`, absolute or relative to the root of the repository, to a normalized path
+    /// relative to the root
+    pub fn repo_path(&self, path: &Path) -> Result<PathBuf> {
+        let outside = || anyhow!("'{}' is outside of the repository", path.display());
+        let relpath = if path.is_absolute() {
+            let path = normalize(path).ok_or_else(outside)?;
+            match path.strip_prefix(&self.prefix) {
+                Ok(relpath) => relpath.to_path_buf(),
+                // The working directory may be given through a symbolic link
+                Err(_) => {
+                    let prefix = fs::canonicalize(&self.prefix).map_err(|_| outside())?;
+                    path.strip_prefix(prefix).map_err(|_| outside())?.into()
+                }
+            }
+        } else {
+            normalize(path).ok_or_else(outside)?
+        };
+        if relpath.as_os_str().is_empty() {
+            return Err(anyhow!("'{}' is the root of the repository", path.display()).into());
+        }
+        Ok(relpath)
+    }
+
+    fn repo_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        paths.iter().map(|path| self.repo_path(path)).collect()
+    }
+
+    /// Index of the rule applying to `path`
+    fn rule_index(&self, path: &Path) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|rule| rule.paths.iter().any(|p| p == path))
+    }
+
+    /// Index of the rule applying to `path`, split off into a rule of its own first if it also
+    /// applies to paths which aren't in `changed`
+    fn own_rule(&mut self, path: &Path, changed: &[PathBuf]) -> Option<usize> {
+        let i = self.rule_index(path)?;
+        if self.rules[i].paths.iter().all(|p| changed.contains(p)) {
+            return Some(i);
+        }
+        let mut rule = self.rules[i].clone();
+        rule.paths = vec![path.into()];
+        self.rules[i].paths.retain(|p| p != path);
+        self.rules.insert(i, rule);
+        Some(i)
+    }
+
+    /// Option of `path` given by `option`, taken from the rule of the path, or else from the
+    /// first of the comma separated recipient `groups` setting it, falling back to the default
+    /// rule for paths with neither
+    fn option<T>(
+        &self,
+        path: &Path,
+        groups: Option<&str>,
+        option: impl Fn(&Rule) -> Option<T>,
+    ) -> Option<T> {
+        let path = path.strip_prefix(&self.prefix).ok()?;
+        match (self.rule_index(path), groups) {
+            (Some(i), _) => option(&self.rules[i]),
+            (None, Some(groups)) => groups
+                .split(',')
+                .filter_map(|name| self.groups.get(name.trim()))
+                .find_map(option),
+            (None, None) => self.default.as_ref().and_then(option),
+        }
+    }
+
+    /// Removes `recipients` given by their key, label or short fingerprint
+    pub fn remove(
+        &mut self,
+        recipients: Vec<String>,
+        recipients_files: Vec<PathBuf>,
+        paths: Vec<PathBuf>,
+    ) -> Result<()> {
+        let recipients_files = self.repo_paths(&recipients_files)?;
+        let paths = self.repo_paths(&paths)?;
+        let rules = if paths.is_empty() {
+            let groups = self.groups.values();
+            self.rules
+                .iter()
+                .chain(&self.default)
+                .chain(groups)
+                .collect()
+        } else {
+            paths
+                .iter()
+                .map(|path| {
+                    let i = self.rule_index(path).with_context(|| {
+                        format!("No configuration entry found for {}", path.display())
+                    })?;
+                    Ok(&self.rules[i])
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+        // Recipients only held in escrow can be removed from everywhere
+        let escrow = if paths.is_empty() {
+            &self.escrow[..]
+        } else {
+            &[]
+        };
+        let resolved = resolve_recipients(&recipients, &rules, escrow)?;
+        let removed = |r: &Recipient| resolved.iter().any(|x| x.same_key(r));
+
         if paths.is_empty() {
-            for rs in self.config.values_mut() {
-                rs.retain(|r| !recipients.contains(r));
+            self.escrow.retain(|r| !removed(r));
+            let groups = self.groups.values_mut();
+            for rule in self.rules.iter_mut().chain(&mut self.default).chain(groups) {
+                rule.remove(removed, &recipients_files);
             }
         } else {
-            for path in paths {
-                let rs = self.config.get_mut(&path).with_context(|| {
-                    format!("No configuration entry found for {}", path.display())
-                })?;
-                if recipients.is_empty() {
-                    rs.clear();
+            for path in &paths {
+                let Some(i) = self.own_rule(path, &paths) else {
+                    // Listed more than once and already removed
+                    continue;
+                };
+                let rule = &mut self.rules[i];
+                if recipients.is_empty() && recipients_files.is_empty() {
+                    rule.paths.retain(|p| p != path);
                 } else {
-                    rs.retain(|r| !recipients.contains(r));
+                    rule.remove(removed, &recipients_files);
                 }
             }
         }
 
-        self.config.retain(|_, rs| !rs.is_empty());
+        self.rules
+            .retain(|rule| !rule.is_empty() && !rule.paths.is_empty());
+        self.default = self.default.take().filter(|rule| !rule.is_empty());
+        self.groups.retain(|_, group| !group.is_empty());
 
         Ok(())
     }
 
+    /// Removes `recipients` from the default rule, or the whole rule if nothing is given
+    pub fn remove_default(
+        &mut self,
+        recipients: Vec<String>,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        if self.default.is_none() {
+            return Err(anyhow!("No default recipients are configured").into());
+        }
+        let recipients_files = self.repo_paths(&recipients_files)?;
+        remove_from(&mut self.default, recipients, recipients_files)
+    }
+
+    /// Removes `recipients` from the group `name`, or the whole group if nothing is given
+    pub fn remove_group(
+        &mut self,
+        name: &str,
+        recipients: Vec<String>,
+        recipients_files: Vec<PathBuf>,
+    ) -> Result<()> {
+        let recipients_files = self.repo_paths(&recipients_files)?;
+        let mut group = self.groups.remove(name);
+        if group.is_none() {
+            return Err(anyhow!("No recipient group '{}' is configured", name).into());
+        }
+        let result = remove_from(&mut group, recipients, recipients_files);
+        if let Some(group) = group {
+            self.groups.insert(name.into(), group);
+        }
+        result
+    }
+
+    /// Whether there is a default rule for protected paths without a rule of their own
+    pub fn has_default(&self) -> bool {
+        self.default.is_some()
+    }
+
+    /// Marks `recipients` as held in escrow
+    pub fn add_escrow(&mut self, recipients: &[String]) {
+        for key in recipients {
+            if !self.is_escrow(key) {
+                self.escrow.push(Recipient::new(key.clone(), None));
+            }
+        }
+    }
+
+    /// File of the SSH keys allowed to sign ciphertexts, if signatures are checked
+    pub fn allowed_signers(&self) -> Option<PathBuf> {
+        self.allowed_signers
+            .as_ref()
+            .map(|file| self.prefix.join(file))
+    }
+
+    /// Whether the recipient `key` is held in escrow
+    pub fn is_escrow(&self, key: &str) -> bool {
+        let key = age::recipient_key(key);
+        self.escrow
+            .iter()
+            .any(|r| age::recipient_key(r.key()) == key)
+    }
+
     pub fn list(&self) -> Vec<(String, String)> {
         let mut rv = vec![];
-        for (p, rs) in &self.config {
-            for r in rs {
-                rv.push((p.to_string_lossy().to_string(), r.clone()));
+        let rules = self.rules.iter().map(|rule| {
+            let paths: Vec<_> = rule.paths.iter().map(|p| p.to_string_lossy()).collect();
+            (paths.join(", "), rule)
+        });
+        let default = self.default.iter().map(|rule| ("(default)".into(), rule));
+        let groups = self
+            .groups
+            .iter()
+            .map(|(name, group)| (format!("(group {})", name), group));
+        for (p, rule) in rules.chain(default).chain(groups) {
+            for r in &rule.recipients {
+                let recipient = if self.is_escrow(r.key()) {
+                    format!("{}, escrow", r)
+                } else {
+                    r.to_string()
+                };
+                rv.push((p.clone(), recipient));
+            }
+            for f in &rule.recipients_file {
+                rv.push((p.clone(), format!("recipients from '{}'", f.display())));
             }
         }
         rv
     }
 
-    pub fn get_public_keys(&self, path: &Path) -> Result<&[String]> {
-        let pubk = self
-            .config
-            .get(path.strip_prefix(&self.prefix).with_context(|| {
-                format!(
-                    "Not a path inside git repository, path={path:?}, repo={:?}",
-                    self.prefix
+    /// Paths with a rule, relative to the root of the repository
+    pub fn paths(&self) -> Vec<&Path> {
+        let mut paths: Vec<&Path> = self
+            .rules
+            .iter()
+            .flat_map(|rule| rule.paths.iter().map(|p| p.as_path()))
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// Returns the no-key policy set for `path` in its rule or recipient `groups`, if any
+    pub fn no_key_policy(&self, path: &Path, groups: Option<&str>) -> Option<NoKeyPolicy> {
+        self.option(path, groups, |rule| rule.no_key)
+    }
+
+    /// Whether `path` is encrypted in the ASCII armored format
+    pub fn armor(&self, path: &Path, groups: Option<&str>) -> bool {
+        self.option(path, groups, |rule| rule.armor.then_some(()))
+            .is_some()
+    }
+
+    /// Returns the compression set for `path` in its rule or recipient `groups`, if any
+    pub fn compression(&self, path: &Path, groups: Option<&str>) -> Option<Compression> {
+        self.option(path, groups, |rule| rule.compress)
+    }
+
+    /// Returns the padding set for `path` in its rule or recipient `groups`, if any
+    pub fn padding(&self, path: &Path, groups: Option<&str>) -> Option<Padding> {
+        self.option(path, groups, |rule| rule.padding)
+    }
+
+    /// Returns the recipients for `path`, including the ones read from recipient files
+    ///
+    /// Paths without a rule of their own are encrypted to the comma separated recipient
+    /// `groups` assigned to them by the `agecrypt-recipients` attribute, if any, or fall back
+    /// to the default rule.
+    pub fn get_recipients(&self, path: &Path, groups: Option<&str>) -> Result<Vec<Recipient>> {
+        let relpath = path.strip_prefix(&self.prefix).with_context(|| {
+            format!(
+                "Not a path inside git repository, path={path:?}, repo={:?}",
+                self.prefix
+            )
+        })?;
+        let rule = match (self.rule_index(relpath), groups) {
+            (Some(i), _) => &self.rules[i],
+            (None, Some(groups)) => {
+                return Ok(self.group_recipients(groups).with_context(|| {
+                    format!("Couldn't find the recipients of '{}'", path.display())
+                })?);
+            }
+            (None, None) => self
+                .default
+                .as_ref()
+                .with_context(|| format!("No public key can be found for '{}'", path.display()))?,
+        };
+        self.rule_recipients(rule)
+    }
+
+    /// Recipients of the comma separated recipient `groups`
+    fn group_recipients(&self, groups: &str) -> Result<Vec<Recipient>> {
+        let mut recipients: Vec<Recipient> = vec![];
+        for name in groups.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let group = self
+                .groups
+                .get(name)
+                .with_context(|| format!("Unknown recipient group '{}'", name))?;
+            for recipient in self.rule_recipients(group)? {
+                if !recipients.iter().any(|r| r.same_key(&recipient)) {
+                    recipients.push(recipient);
+                }
+            }
+        }
+        if recipients.is_empty() {
+            return Err(anyhow!("No recipients in the recipient groups '{}'", groups).into());
+        }
+        Ok(recipients)
+    }
+
+    /// Every recipient of the rules, the default rule and the groups, including the ones read
+    /// from recipient files
+    pub fn recipients(&self) -> Result<Vec<Recipient>> {
+        let mut recipients: Vec<Recipient> = vec![];
+        let rules = self.rules.iter().chain(&self.default);
+        for rule in rules.chain(self.groups.values()) {
+            for recipient in self.rule_recipients(rule)? {
+                if !recipients.iter().any(|r| r.same_key(&recipient)) {
+                    recipients.push(recipient);
+                }
+            }
+        }
+        Ok(recipients)
+    }
+
+    fn rule_recipients(&self, rule: &Rule) -> Result<Vec<Recipient>> {
+        let mut recipients = rule.recipients.clone();
+        for file in &rule.recipients_file {
+            let keys = age::read_recipients_file(self.prefix.join(file))?;
+            recipients.extend(keys.into_iter().map(|key| Recipient::new(key, None)));
+        }
+        Ok(recipients)
+    }
+}
+
+/// Resolves `.` and `..` in `path` without looking at the file system, `None` if it goes above
+/// its root
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut rv = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !rv.pop() {
+                    return None;
+                }
+            }
+            component => rv.push(component),
+        }
+    }
+    Some(rv)
+}
+
+/// Removes `recipients` and `recipients_files` from `rule`, or the whole rule if nothing is
+/// given or nothing is left
+fn remove_from(
+    rule: &mut Option<Rule>,
+    recipients: Vec<String>,
+    recipients_files: Vec<PathBuf>,
+) -> Result<()> {
+    let Some(existing) = rule else {
+        return Ok(());
+    };
+    if recipients.is_empty() && recipients_files.is_empty() {
+        *rule = None;
+        return Ok(());
+    }
+    let resolved = resolve_recipients(&recipients, &[existing], &[])?;
+    existing.remove(
+        |r| resolved.iter().any(|x| x.same_key(r)),
+        &recipients_files,
+    );
+    *rule = rule.take().filter(|rule| !rule.is_empty());
+    Ok(())
+}
+
+/// Resolves each of `queries` to exactly one recipient in `rules` or `escrow`
+fn resolve_recipients(
+    queries: &[String],
+    rules: &[&Rule],
+    escrow: &[Recipient],
+) -> Result<Vec<Recipient>> {
+    let candidates: Vec<&Recipient> = rules
+        .iter()
+        .flat_map(|rule| &rule.recipients)
+        .chain(escrow)
+        .collect();
+    let recipients = find_recipients(queries, &candidates)?;
+    Ok(recipients.into_iter().cloned().collect())
+}
+
+/// Resolves each of `queries`, a key, label or short or long fingerprint, to exactly one of `candidates`
+pub(crate) fn find_recipients<'a>(
+    queries: &[String],
+    candidates: &[&'a Recipient],
+) -> Result<Vec<&'a Recipient>> {
+    let mut rv = vec![];
+    for query in queries {
+        let mut matching: Vec<&Recipient> = vec![];
+        for recipient in candidates {
+            if recipient.matches(query) && !matching.iter().any(|m| m.same_key(recipient)) {
+                matching.push(recipient);
+            }
+        }
+        match &matching[..] {
+            [] => return Err(Error::NotExist(query.clone())),
+            [recipient] => rv.push(*recipient),
+            _ => {
+                let matching: Vec<String> = matching.iter().map(|r| r.to_string()).collect();
+                return Err(anyhow!(
+                    "Recipient '{}' is ambiguous, it matches: {}",
+                    query,
+                    matching.join(", ")
                 )
-            })?)
-            .with_context(|| format!("No public key can be found for '{}'", path.display()))?;
-        Ok(&pubk[..])
+                .into());
+            }
+        }
+    }
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+
+    fn recipient() -> String {
+        ::age::x25519::Identity::generate().to_public().to_string()
+    }
+
+    fn rule(cfg: &AppConfig, path: impl AsRef<Path>) -> &Rule {
+        let path = cfg.repo_path(path.as_ref()).unwrap();
+        &cfg.rules[cfg.rule_index(&path).unwrap()]
+    }
+
+    fn ssh_recipient() -> &'static str {
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHsKLqeplhpW+uObz5dvMgjz1OxfM/XXUB+VHtZ6isGN alice@example.com"
+    }
+
+    #[test]
+    fn test_parse_plain_and_detailed_rules() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let r = recipient();
+        let contents = format!(
+            "[config]\n\"a.txt\" = [\"{r}\"]\n\"b.txt\" = {{ recipients_file = \"keys.txt\" }}\n\"c.txt\" = {{ recipients = [\"{r}\"], recipients_file = [\"one.txt\", \"two.txt\"] }}\n"
+        );
+        let cfg = AppConfig::parse(&contents, Path::new("git-agecrypt.toml"), dir.path())?;
+
+        assert_eq!(rule(&cfg, "a.txt").recipients[0].key(), r);
+        assert_eq!(
+            rule(&cfg, "b.txt").recipients_file,
+            vec![PathBuf::from("keys.txt")]
+        );
+        assert_eq!(rule(&cfg, "c.txt").recipients_file.len(), 2);
+
+        let reparsed =
+            AppConfig::parse(&cfg.to_toml()?, Path::new("git-agecrypt.toml"), dir.path())?;
+        assert!(reparsed.rules == cfg.rules);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_key_policy() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "[config]\n\"a.txt\" = {{ recipients = [\"{r}\"], no_key = \"leave-encrypted\" }}\n\"b.txt\" = [\"{r}\"]\n"
+        );
+        let cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        assert_eq!(
+            cfg.no_key_policy(Path::new("/repo/a.txt"), None),
+            Some(NoKeyPolicy::LeaveEncrypted)
+        );
+        assert_eq!(cfg.no_key_policy(Path::new("/repo/b.txt"), None), None);
+        assert_eq!(cfg.no_key_policy(Path::new("/repo/c.txt"), None), None);
+        assert!(cfg.to_toml()?.contains("no_key = \"leave-encrypted\""));
+
+        let contents =
+            format!("[config]\n\"a.txt\" = {{ recipients = [\"{r}\"], no_key = \"skip\" }}\n");
+        assert!(AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo")
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_field_is_error() {
+        let r = recipient();
+        let contents = format!("version = 1\nescrows = []\n[config]\n\"a.txt\" = [\"{r}\"]\n");
+        assert!(AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo")
+        )
+        .is_err());
+        let contents = contents.replace("escrows", "escrow");
+        assert!(AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo")
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_unknown_rule_field_is_error() {
+        let contents = "[config]\n\"a.txt\" = { recipient_files = \"keys.txt\" }\n";
+        assert!(
+            AppConfig::parse(contents, Path::new("git-agecrypt.toml"), Path::new("/repo")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_public_keys_reads_recipients_file() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        let inline = recipient();
+        let from_file = recipient();
+        dir.child("keys.txt")
+            .write_str(&format!(
+                "# ops team\n\n{from_file}\n  {}  \n",
+                ssh_recipient()
+            ))
+            .unwrap();
+        let contents = format!(
+            "[config]\n\"secret.txt\" = {{ recipients = [\"{inline}\"], recipients_file = \"keys.txt\" }}\n"
+        );
+        let cfg = AppConfig::parse(&contents, Path::new("git-agecrypt.toml"), dir.path())?;
+
+        let recipients = cfg.get_recipients(&dir.join("secret.txt"), None)?;
+        let keys: Vec<&str> = recipients.iter().map(|r| r.key()).collect();
+        assert_eq!(keys, vec![&inline, &from_file, ssh_recipient()]);
+        assert_eq!(recipients[2].label(), Some("alice@example.com"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_recipient_in_file_reports_line() {
+        let dir = TempDir::new().unwrap();
+        dir.child("keys.txt")
+            .write_str(&format!("# ops team\n{}\nnot-a-recipient\n", recipient()))
+            .unwrap();
+        dir.child("secret.txt").touch().unwrap();
+        let mut cfg =
+            AppConfig::parse("[config]\n", Path::new("git-agecrypt.toml"), dir.path()).unwrap();
+
+        let err = cfg
+            .add(
+                vec![],
+                None,
+                vec!["keys.txt".into()],
+                vec![dir.join("secret.txt")],
+                false,
+            )
+            .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("keys.txt"), "{message}");
+        assert!(message.contains("line 3"), "{message}");
+    }
+
+    #[test]
+    fn test_add_and_remove_recipients_file() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("keys.txt")
+            .write_str(&format!("{}\n", recipient()))
+            .unwrap();
+        dir.child("secret.txt").touch().unwrap();
+        let secret = dir.join("secret.txt");
+        let mut cfg = AppConfig::parse("[config]\n", Path::new("git-agecrypt.toml"), dir.path())?;
+
+        // Stored relative to the root of the repository, like the paths
+        cfg.add(
+            vec![],
+            None,
+            vec![dir.join("keys.txt")],
+            vec![secret.clone()],
+            false,
+        )?;
+        assert_eq!(
+            cfg.list(),
+            vec![("secret.txt".into(), "recipients from 'keys.txt'".into())]
+        );
+        assert!(cfg
+            .add(
+                vec![],
+                None,
+                vec!["../keys.txt".into()],
+                vec![secret.clone()],
+                false
+            )
+            .is_err());
+
+        cfg.remove(vec![], vec!["keys.txt".into()], vec![])?;
+        assert!(cfg.list().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_labeled_recipient_round_trip() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "[config]\n\"a.txt\" = [{{ key = \"{r}\", label = \"bob@example.com\" }}, \"{}\"]\n",
+            ssh_recipient()
+        );
+        let cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        let recipients = &rule(&cfg, "a.txt").recipients;
+        assert_eq!(recipients[0].label(), Some("bob@example.com"));
+        assert_eq!(recipients[1].label(), Some("alice@example.com"));
+        let listed: Vec<String> = cfg.list().into_iter().map(|(_, r)| r).collect();
+        assert_eq!(
+            listed,
+            vec![
+                format!("bob@example.com ({})", age::fingerprint(&r)),
+                format!("alice@example.com ({})", age::fingerprint(ssh_recipient())),
+            ]
+        );
+
+        let reparsed = AppConfig::parse(
+            &cfg.to_toml()?,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert!(reparsed.rules == cfg.rules);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_rule() -> Result<()> {
+        let r = recipient();
+        let mut cfg = AppConfig::parse(
+            &format!(
+                "default = [\"{r}\"]\n\n[config]\n\"a.txt\" = [\"{}\"]\n",
+                ssh_recipient()
+            ),
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        let keys = |cfg: &AppConfig, path: &str| -> Result<Vec<String>> {
+            let recipients = cfg.get_recipients(&Path::new("/repo").join(path), None)?;
+            Ok(recipients.iter().map(|r| r.key().to_string()).collect())
+        };
+        assert_eq!(keys(&cfg, "a.txt")?, vec![ssh_recipient()]);
+        assert_eq!(keys(&cfg, "b.txt")?, vec![r.clone()]);
+        assert!(cfg.paths() == vec![Path::new("a.txt")]);
+
+        cfg.add_default(vec![ssh_recipient().into()], None, vec![])?;
+        let reparsed = AppConfig::parse(
+            &cfg.to_toml()?,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert_eq!(
+            keys(&reparsed, "b.txt")?,
+            vec![r.clone(), ssh_recipient().into()]
+        );
+
+        // Removing a recipient everywhere includes the default rule
+        cfg.remove(vec!["alice@example.com".into()], vec![], vec![])?;
+        assert_eq!(keys(&cfg, "b.txt")?, vec![r.clone()]);
+        // The rule of `a.txt` is removed with its last recipient
+        assert_eq!(keys(&cfg, "a.txt")?, vec![r.clone()]);
+
+        cfg.remove_default(vec![], vec![])?;
+        assert!(!cfg.has_default());
+        assert!(cfg.get_recipients(Path::new("/repo/b.txt"), None).is_err());
+        assert!(cfg.remove_default(vec![], vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_escrow_recipients() -> Result<()> {
+        let r = recipient();
+        let mut cfg = AppConfig::parse(
+            &format!("[config]\n\"a.txt\" = [\"{r}\", \"{}\"]\n", ssh_recipient()),
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        cfg.add_escrow(&[ssh_recipient().into()]);
+        // SSH keys are matched regardless of their comment
+        assert!(cfg.is_escrow(ssh_recipient().rsplit_once(' ').unwrap().0));
+        assert!(!cfg.is_escrow(&r));
+
+        let reparsed = AppConfig::parse(
+            &cfg.to_toml()?,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert!(reparsed.is_escrow(ssh_recipient()));
+        assert_eq!(
+            reparsed.list()[1].1,
+            format!(
+                "alice@example.com ({}), escrow",
+                age::fingerprint(ssh_recipient())
+            )
+        );
+
+        cfg.remove(vec!["alice@example.com".into()], vec![], vec![])?;
+        assert!(!cfg.is_escrow(ssh_recipient()));
+
+        // Recipients only held in escrow are found by their key or fingerprint as well
+        let offline = recipient();
+        cfg.add_escrow(std::slice::from_ref(&offline));
+        assert_matches!(
+            cfg.remove(vec![offline.clone()], vec![], vec!["a.txt".into()]),
+            Err(Error::NotExist(_))
+        );
+        cfg.remove(vec![age::fingerprint(&offline)], vec![], vec![])?;
+        assert!(!cfg.is_escrow(&offline));
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_fingerprint_different_keys() -> Result<()> {
+        // Distinct keys with the same short fingerprint
+        let (a, b) = ("age1collision46528", "age1collision69016");
+        assert_eq!(age::fingerprint(a), age::fingerprint(b));
+        let mut cfg = AppConfig::parse(
+            &format!("[config]\n\"a.txt\" = [\"{a}\"]\n"),
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        cfg.rules[0].add(&[b.into()], &None, &[]);
+        assert_eq!(cfg.rules[0].recipients.len(), 2);
+
+        cfg.add_escrow(&[a.into()]);
+        assert!(!cfg.is_escrow(b));
+        cfg.add_escrow(&[b.into()]);
+        assert_eq!(cfg.escrow.len(), 2);
+
+        cfg.remove(vec![a.into()], vec![], vec![])?;
+        let keys: Vec<_> = cfg.rules[0].recipients.iter().map(|r| r.key()).collect();
+        assert_eq!(keys, vec![b]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_label_requires_single_recipient() {
+        let dir = TempDir::new().unwrap();
+        dir.child("secret.txt").touch().unwrap();
+        let mut cfg =
+            AppConfig::parse("[config]\n", Path::new("git-agecrypt.toml"), dir.path()).unwrap();
+
+        let result = cfg.add(
+            vec![recipient(), recipient()],
+            Some("ops".into()),
+            vec![],
+            vec![dir.join("secret.txt")],
+            false,
+        );
+        assert!(result.is_err());
+    }
+
+    #[rstest]
+    #[case::key(None)]
+    #[case::label(Some("bob"))]
+    #[case::ssh_comment(Some("alice@example.com"))]
+    #[case::fingerprint(Some("fingerprint"))]
+    fn test_remove_recipient_by(#[case] query: Option<&str>) -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("secret.txt").touch().unwrap();
+        let secret = dir.join("secret.txt");
+        let bob = recipient();
+        let mut cfg = AppConfig::parse("[config]\n", Path::new("git-agecrypt.toml"), dir.path())?;
+        cfg.add(
+            vec![bob.clone()],
+            Some("bob".into()),
+            vec![],
+            vec![secret.clone()],
+            false,
+        )?;
+        cfg.add(
+            vec![ssh_recipient().into()],
+            None,
+            vec![],
+            vec![secret.clone()],
+            false,
+        )?;
+
+        let (query, remaining) = match query {
+            None => (bob.clone(), "alice@example.com"),
+            Some("fingerprint") => (age::fingerprint(&bob), "alice@example.com"),
+            Some("alice@example.com") => ("alice@example.com".into(), "bob"),
+            Some(label) => (label.into(), "alice@example.com"),
+        };
+        cfg.remove(vec![query], vec![], vec![])?;
+
+        let recipients = &rule(&cfg, &secret).recipients;
+        assert_eq!(recipients.len(), 1);
+        assert_eq!(recipients[0].label(), Some(remaining));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_unknown_or_ambiguous_recipient() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("secret.txt").touch().unwrap();
+        let secret = dir.join("secret.txt");
+        let mut cfg = AppConfig::parse("[config]\n", Path::new("git-agecrypt.toml"), dir.path())?;
+        cfg.add(
+            vec![recipient()],
+            Some("ops".into()),
+            vec![],
+            vec![secret.clone()],
+            false,
+        )?;
+        cfg.add(
+            vec![recipient()],
+            Some("ops".into()),
+            vec![],
+            vec![secret.clone()],
+            false,
+        )?;
+
+        assert_matches!(
+            cfg.remove(vec!["nobody".into()], vec![], vec![]),
+            Err(Error::NotExist(_))
+        );
+        let err = cfg.remove(vec!["ops".into()], vec![], vec![]).unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{err}");
+        assert_eq!(rule(&cfg, &secret).recipients.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rule_list() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "version = 2\n\n[[rules]]\npaths = [\"a.txt\", \"b.txt\"]\nrecipients = [\"{r}\"]\narmor = true\ncompress = \"zstd\"\n\n[[rules]]\npaths = [\"b.txt\", \"c.txt\"]\nrecipients = [\"{}\"]\npadding = {{ bucket = 4096 }}\n",
+            ssh_recipient()
+        );
+        let cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        assert_eq!(cfg.version(), 2);
+        // The first rule listing a path applies to it
+        assert_eq!(rule(&cfg, "b.txt").recipients[0].key(), r);
+        assert_eq!(rule(&cfg, "c.txt").recipients[0].key(), ssh_recipient());
+        assert!(cfg.armor(Path::new("/repo/a.txt"), None));
+        assert!(!cfg.armor(Path::new("/repo/c.txt"), None));
+        assert_eq!(
+            cfg.compression(Path::new("/repo/a.txt"), None),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(cfg.compression(Path::new("/repo/c.txt"), None), None);
+        assert_eq!(cfg.padding(Path::new("/repo/a.txt"), None), None);
+        assert_eq!(
+            cfg.padding(Path::new("/repo/c.txt"), None),
+            Some(Padding::Bucket(4096))
+        );
+        assert!(cfg.paths() == vec![Path::new("a.txt"), Path::new("b.txt"), Path::new("c.txt")]);
+
+        let reparsed = AppConfig::parse(
+            &cfg.to_toml()?,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert!(reparsed.rules == cfg.rules);
+
+        let contents = format!(
+            "version = 2\n\n[[rules]]\npaths = [\"a.txt\"]\nrecipients = [\"{r}\"]\npadding = 1\n"
+        );
+        assert!(AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo")
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::newer("version = 3\n", "Unsupported configuration version 3")]
+    #[case::not_a_number("version = \"2\"\n", "Invalid configuration version")]
+    #[case::armor_in_v1(
+        "[config]\n\"a.txt\" = { recipients_file = \"keys.txt\", armor = true }\n",
+        "requires configuration version 2"
+    )]
+    #[case::padding_in_v1(
+        "[config]\n\"a.txt\" = { recipients_file = \"keys.txt\", padding = \"power-of-two\" }\n",
+        "The 'padding' option requires configuration version 2"
+    )]
+    #[case::compress_in_v1(
+        "[config]\n\"a.txt\" = { recipients_file = \"keys.txt\", compress = \"zstd\" }\n",
+        "The 'compress' option requires configuration version 2"
+    )]
+    #[case::signers_in_v1(
+        "allowed_signers = \"keys/allowed_signers\"\n[config]\n",
+        "Signed ciphertexts require configuration version 2"
+    )]
+    fn test_unsupported_version(#[case] contents: &str, #[case] expected: &str) {
+        let err = AppConfig::parse(contents, Path::new("git-agecrypt.toml"), Path::new("/repo"))
+            .err()
+            .unwrap();
+        let message = format!("{err:#}");
+        assert!(message.contains(expected), "{message}");
+    }
+
+    #[test]
+    fn test_migrate() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "default = [\"{r}\"]\n\n[config]\n\"a.txt\" = [\"{r}\"]\n\"b.txt\" = [\"{}\"]\n\"c.txt\" = [\"{r}\"]\n",
+            ssh_recipient()
+        );
+        let mut cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert_eq!(cfg.version(), 1);
+        // Saving keeps the version the file was written in
+        assert!(!cfg.to_toml()?.contains("version"));
+
+        assert_eq!(cfg.migrate(), Some(1));
+        assert_eq!(cfg.migrate(), None);
+        let paths: Vec<&Vec<PathBuf>> = cfg.rules.iter().map(|rule| &rule.paths).collect();
+        assert_eq!(
+            paths,
+            vec![
+                &vec![PathBuf::from("a.txt"), PathBuf::from("c.txt")],
+                &vec![PathBuf::from("b.txt")]
+            ]
+        );
+
+        let toml = cfg.to_toml()?;
+        assert!(toml.starts_with("version = 2\n"), "{toml}");
+        let reparsed = AppConfig::parse(&toml, Path::new("git-agecrypt.toml"), Path::new("/repo"))?;
+        assert_eq!(reparsed.version(), 2);
+        assert!(reparsed.rules == cfg.rules);
+        assert!(reparsed.default == cfg.default);
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_path_of_shared_rule() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("a.txt").touch().unwrap();
+        dir.child("b.txt").touch().unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        let r = recipient();
+        let mut cfg =
+            AppConfig::parse("version = 2\n", Path::new("git-agecrypt.toml"), dir.path())?;
+
+        cfg.add(
+            vec![r.clone()],
+            None,
+            vec![],
+            vec![a.clone(), b.clone()],
+            false,
+        )?;
+        assert_eq!(cfg.rules.len(), 1);
+        // Adding to every path of a rule keeps it shared
+        cfg.add(
+            vec![ssh_recipient().into()],
+            None,
+            vec![],
+            vec![b.clone(), a.clone()],
+            false,
+        )?;
+        assert_eq!(cfg.rules.len(), 1);
+
+        // Changing a single path splits it off into a rule of its own
+        cfg.remove(vec![r.clone()], vec![], vec![a.clone()])?;
+        assert_eq!(cfg.rules.len(), 2);
+        assert_eq!(rule(&cfg, &a).recipients.len(), 1);
+        assert_eq!(rule(&cfg, &b).recipients.len(), 2);
+
+        cfg.remove(vec![], vec![], vec![b.clone(), b.clone()])?;
+        assert_eq!(cfg.paths(), vec![Path::new("a.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths_are_relative_to_repository() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("a.txt").touch().unwrap();
+        dir.child("sub/b.txt").touch().unwrap();
+        let r = recipient();
+        let mut cfg = AppConfig::parse(
+            "version = 2\n[[rules]]\npaths = [\"./sub/b.txt\"]\nrecipients = []\n",
+            Path::new("git-agecrypt.toml"),
+            dir.path(),
+        )?;
+        assert_eq!(cfg.paths(), vec![Path::new("sub/b.txt")]);
+
+        cfg.add(vec![r.clone()], None, vec![], vec!["./a.txt".into()], false)?;
+        cfg.add(
+            vec![r.clone()],
+            None,
+            vec![],
+            vec![dir.join("sub/../a.txt")],
+            false,
+        )?;
+        cfg.add(
+            vec![r.clone()],
+            None,
+            vec![],
+            vec!["sub/./b.txt".into()],
+            false,
+        )?;
+        assert_eq!(
+            cfg.paths(),
+            vec![Path::new("a.txt"), Path::new("sub/b.txt")]
+        );
+        assert_eq!(rule(&cfg, "a.txt").recipients.len(), 1);
+
+        cfg.remove(vec![], vec![], vec![dir.join("a.txt")])?;
+        assert_eq!(cfg.paths(), vec![Path::new("sub/b.txt")]);
+
+        for outside in [
+            PathBuf::from("../a.txt"),
+            dir.path().into(),
+            "/a.txt".into(),
+        ] {
+            assert_matches!(
+                cfg.add(vec![r.clone()], None, vec![], vec![outside], true),
+                Err(_)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_missing_path() -> Result<()> {
+        let dir = TempDir::new().unwrap();
+        dir.child("sub").create_dir_all().unwrap();
+        let r = recipient();
+        let mut cfg =
+            AppConfig::parse("version = 2\n", Path::new("git-agecrypt.toml"), dir.path())?;
+
+        let err = cfg
+            .add(vec![r.clone()], None, vec![], vec!["new.txt".into()], false)
+            .unwrap_err();
+        assert!(err.to_string().contains("--allow-missing"), "{err}");
+        assert_matches!(
+            cfg.add(vec![r.clone()], None, vec![], vec!["sub".into()], true),
+            Err(_)
+        );
+        assert!(cfg.paths().is_empty());
+
+        cfg.add(vec![r], None, vec![], vec!["new.txt".into()], true)?;
+        assert_eq!(cfg.paths(), vec![Path::new("new.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_keeps_comments() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "# Secrets of the deployment\n[config]\n\"c.txt\" = [\"{r}\"]\n# Owned by ops\n\"a.txt\" = [\n    \"{r}\", # bob\n    \"{}\", # alice\n]\n",
+            ssh_recipient()
+        );
+        let mut cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        cfg.remove(vec![r.clone()], vec![], vec!["a.txt".into()])?;
+        assert_eq!(
+            cfg.to_toml()?,
+            format!(
+                "# Secrets of the deployment\n[config]\n# Owned by ops\n\"a.txt\" = [\n    \"{}\", # alice\n]\n\"c.txt\" = [\"{r}\"]\n",
+                ssh_recipient()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient_groups() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "version = 2\n\n[groups]\nops = [\"{r}\"]\nbackend = [\"{}\", \"{r}\"]\n\n[[rules]]\npaths = [\"a.txt\"]\nrecipients = [\"{}\"]\n",
+            ssh_recipient(),
+            ssh_recipient()
+        );
+        let mut cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        let keys = |cfg: &AppConfig, path: &str, groups| -> Result<Vec<String>> {
+            let recipients = cfg.get_recipients(&Path::new("/repo").join(path), groups)?;
+            Ok(recipients.iter().map(|r| r.key().to_string()).collect())
+        };
+        // Recipients in several groups are only used once
+        assert_eq!(
+            keys(&cfg, "b.txt", Some("ops, backend"))?,
+            vec![r.clone(), ssh_recipient().into()]
+        );
+        // A rule of the path takes precedence
+        assert_eq!(keys(&cfg, "a.txt", Some("ops"))?, vec![ssh_recipient()]);
+        let err = keys(&cfg, "b.txt", Some("ops,dev")).unwrap_err();
+        assert!(format!("{err:#}").contains("Unknown recipient group 'dev'"));
+
+        cfg.add_group("dev".into(), vec![r.clone()], None, vec![])?;
+        assert!(cfg
+            .add_group("dev ops".into(), vec![r.clone()], None, vec![])
+            .is_err());
+        cfg.remove_group("ops", vec![], vec![])?;
+        cfg.remove(vec![r.clone()], vec![], vec![])?;
+        let reparsed = AppConfig::parse(
+            &cfg.to_toml()?,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert!(reparsed.groups.keys().eq(["backend"]));
+        assert_eq!(
+            keys(&reparsed, "b.txt", Some("backend"))?,
+            vec![ssh_recipient()]
+        );
+
+        // Groups can't be represented in version 1
+        let contents = format!("[config]\n\"a.txt\" = [\"{r}\"]\n\n[groups]\nops = [\"{r}\"]\n");
+        assert!(AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo")
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_recipient_group_options() -> Result<()> {
+        let r = recipient();
+        let contents = format!(
+            "version = 2\n\n[default]\nrecipients = [\"{r}\"]\ncompress = \"zstd\"\n\n[groups]\nops = {{ recipients = [\"{r}\"], armor = true, no_key = \"leave-encrypted\" }}\nbackend = {{ recipients = [\"{r}\"], padding = \"power-of-two\" }}\n\n[[rules]]\npaths = [\"a.txt\"]\nrecipients = [\"{r}\"]\n"
+        );
+        let cfg = AppConfig::parse(
+            &contents,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+
+        // A rule of the path takes precedence over its groups
+        let a = Path::new("/repo/a.txt");
+        assert!(!cfg.armor(a, Some("ops")));
+        assert_eq!(cfg.no_key_policy(a, Some("ops")), None);
+        // Grouped paths take their options from the groups instead of the default rule
+        let b = Path::new("/repo/b.txt");
+        assert!(cfg.armor(b, Some("backend,ops")));
+        assert_eq!(
+            cfg.no_key_policy(b, Some("ops")),
+            Some(NoKeyPolicy::LeaveEncrypted)
+        );
+        assert_eq!(
+            cfg.padding(b, Some("ops, backend")),
+            Some(Padding::PowerOfTwo)
+        );
+        assert_eq!(cfg.compression(b, Some("ops")), None);
+        assert_eq!(cfg.compression(b, None), Some(Compression::Zstd));
+
+        let reparsed = AppConfig::parse(
+            &cfg.to_toml()?,
+            Path::new("git-agecrypt.toml"),
+            Path::new("/repo"),
+        )?;
+        assert!(reparsed.groups == cfg.groups);
+        Ok(())
     }
 }
@@ -0,0 +1,279 @@
+//! Format preserving updates of `git-agecrypt.toml`
+//!
+//! The configuration is serialized from scratch and merged into the document it was loaded
+//! from, so that only the items which changed are rewritten. Comments, e.g. annotating who owns
+//! a key, and the layout of everything else are kept.
+
+use toml_edit::{Array, ArrayOfTables, DocumentMut, Item, Table, TableLike, Value};
+
+/// Updates `document` to have the contents of `fresh`
+pub(super) fn merge(document: &mut DocumentMut, fresh: &DocumentMut) {
+    merge_table(document.as_table_mut(), fresh.as_table());
+}
+
+fn merge_item(old: &mut Item, new: &Item) {
+    if same_item(old, new) {
+        return;
+    }
+    match (old, new) {
+        (Item::Table(old), Item::Table(new)) => merge_table(old, new),
+        (Item::ArrayOfTables(old), Item::ArrayOfTables(new)) => merge_tables(old, new),
+        (Item::Value(old), Item::Value(new)) => merge_value(old, new),
+        (old, new) => *old = fresh_item(new),
+    }
+}
+
+fn merge_table(old: &mut dyn TableLike, new: &dyn TableLike) {
+    let removed: Vec<String> = old
+        .iter()
+        .filter(|(key, _)| !new.contains_key(key))
+        .map(|(key, _)| key.to_owned())
+        .collect();
+    for key in removed {
+        old.remove(&key);
+    }
+    for (key, item) in new.iter() {
+        match old.get_mut(key) {
+            Some(existing) => merge_item(existing, item),
+            None => {
+                // Keeps the quoting of the key
+                let (key, _) = new.get_key_value(key).unwrap();
+                old.entry_format(key).or_insert(fresh_item(item));
+            }
+        }
+    }
+}
+
+/// Merges arrays of tables, i.e. the rules of the configuration
+///
+/// Unchanged tables are kept as they are, changed ones are matched by their `paths`: the same
+/// ones first, then any of them, e.g. when a path is split off into a rule of its own.
+fn merge_tables(old: &mut ArrayOfTables, new: &ArrayOfTables) {
+    let mut unused: Vec<Option<Table>> = old.iter().cloned().map(Some).collect();
+    let mut merged = ArrayOfTables::new();
+    let paths = |table: &Table| -> Vec<String> {
+        let paths = table.get("paths").and_then(Item::as_array);
+        paths
+            .into_iter()
+            .flatten()
+            .filter_map(|path| path.as_str().map(str::to_owned))
+            .collect()
+    };
+    for table in new.iter() {
+        let new_paths = paths(table);
+        let existing = take_first(&mut unused, |t| same_table(t, table))
+            .or_else(|| take_first(&mut unused, |t| paths(t) == new_paths))
+            .or_else(|| {
+                take_first(&mut unused, |t| {
+                    paths(t).iter().any(|path| new_paths.contains(path))
+                })
+            });
+        match existing {
+            Some(mut existing) => {
+                merge_table(&mut existing, table);
+                merged.push(existing);
+            }
+            None => merged.push(unpositioned(table)),
+        }
+    }
+    *old = merged;
+}
+
+fn merge_value(old: &mut Value, new: &Value) {
+    match (old, new) {
+        (Value::Array(old), Value::Array(new)) => merge_array(old, new),
+        (Value::InlineTable(old), Value::InlineTable(new)) => merge_table(old, new),
+        (old, new) => {
+            let decor = old.decor().clone();
+            *old = new.clone();
+            *old.decor_mut() = decor;
+        }
+    }
+}
+
+/// Merges arrays of values, keeping the formatting and the comments of the unchanged elements
+///
+/// A comment following an element on the same line belongs to it, even though it is parsed as
+/// the prefix of the next element.
+fn merge_array(old: &mut Array, new: &Array) {
+    let multiline = old.iter().any(|v| raw(v.decor().prefix()).contains('\n'));
+    let (opening, first_prefix) = old
+        .get(0)
+        .map(|v| split_line(raw(v.decor().prefix())))
+        .unwrap_or_default();
+    let template = old
+        .iter()
+        .last()
+        .filter(|_| old.len() > 1 || multiline)
+        .map(|v| split_line(raw(v.decor().prefix())).1.to_owned());
+
+    // Elements with their own prefix and the rest of their line after the comma
+    let mut unused = vec![];
+    for (i, value) in old.iter().enumerate() {
+        let next = match old.get(i + 1) {
+            Some(next) => raw(next.decor().prefix()),
+            None => raw(Some(old.trailing())),
+        };
+        let prefix = if i == 0 {
+            first_prefix
+        } else {
+            split_line(raw(value.decor().prefix())).1
+        };
+        let line = format!("{}{}", raw(value.decor().suffix()), split_line(next).0);
+        let value = value.clone().decorated(prefix, "");
+        unused.push(Some((value, line)));
+    }
+    let closing = split_line(raw(Some(old.trailing()))).1.to_owned();
+
+    let mut merged = Array::new();
+    let mut line = opening.to_owned();
+    for value in new.iter() {
+        let (mut value, rest) = match take_first(&mut unused, |(v, _)| same_value(v, value)) {
+            Some(existing) => existing,
+            None => {
+                let prefix = match &template {
+                    Some(template) => template.clone(),
+                    None if merged.is_empty() => String::new(),
+                    None => " ".into(),
+                };
+                let rest = if multiline { "\n" } else { "" };
+                (value.clone().decorated(prefix, ""), rest.to_owned())
+            }
+        };
+        let prefix = format!("{}{}", line, raw(value.decor().prefix()));
+        value.decor_mut().set_prefix(prefix);
+        merged.push_formatted(value);
+        line = rest;
+    }
+    merged.set_trailing(format!("{}{}", line, closing));
+    merged.set_trailing_comma(old.trailing_comma() || (multiline && !merged.is_empty()));
+    *merged.decor_mut() = old.decor().clone();
+    *old = merged;
+}
+
+fn raw(raw: Option<&toml_edit::RawString>) -> &str {
+    raw.and_then(|r| r.as_str()).unwrap_or_default()
+}
+
+/// Splits `raw` after its first line break, if any
+fn split_line(raw: &str) -> (&str, &str) {
+    match raw.find('\n') {
+        Some(i) => raw.split_at(i + 1),
+        None => ("", raw),
+    }
+}
+
+fn take_first<T>(items: &mut [Option<T>], matches: impl Fn(&T) -> bool) -> Option<T> {
+    items
+        .iter_mut()
+        .find(|item| item.as_ref().is_some_and(&matches))
+        .and_then(Option::take)
+}
+
+/// Copy of `item` to be inserted into another document
+///
+/// The position of tables refers to the document they were parsed from, without it they are
+/// placed after the preceding table.
+fn fresh_item(item: &Item) -> Item {
+    match item {
+        Item::Table(table) => Item::Table(unpositioned(table)),
+        Item::ArrayOfTables(tables) => {
+            let mut rv = ArrayOfTables::new();
+            for table in tables.iter() {
+                rv.push(unpositioned(table));
+            }
+            Item::ArrayOfTables(rv)
+        }
+        item => item.clone(),
+    }
+}
+
+fn unpositioned(table: &Table) -> Table {
+    let mut rv = Table::new();
+    for (key, item) in table.iter() {
+        rv.insert(key, fresh_item(item));
+    }
+    rv
+}
+
+fn same_item(a: &Item, b: &Item) -> bool {
+    match (a, b) {
+        (Item::Value(a), Item::Value(b)) => same_value(a, b),
+        (Item::ArrayOfTables(a), Item::ArrayOfTables(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_table(a, b))
+        }
+        _ => match (a.as_table_like(), b.as_table_like()) {
+            (Some(a), Some(b)) => same_table(a, b),
+            _ => false,
+        },
+    }
+}
+
+fn same_table(a: &dyn TableLike, b: &dyn TableLike) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .all(|(key, a)| b.get(key).is_some_and(|b| same_item(a, b)))
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.value() == b.value(),
+        (Value::Integer(a), Value::Integer(b)) => a.value() == b.value(),
+        (Value::Boolean(a), Value::Boolean(b)) => a.value() == b.value(),
+        (Value::Datetime(a), Value::Datetime(b)) => a.value() == b.value(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b))
+        }
+        (Value::InlineTable(a), Value::InlineTable(b)) => same_table(a, b),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case::unchanged(
+        "# owners\n[config]\n\"a\" = [ 'x' ]  # alice\n",
+        "[config]\n\"a\" = [\"x\"]\n",
+        "# owners\n[config]\n\"a\" = [ 'x' ]  # alice\n"
+    )]
+    #[case::append_inline(
+        "[config]\n\"a\" = [\"x\", \"y\"] # team\n",
+        "[config]\n\"a\" = [\"x\", \"y\", \"z\"]\n",
+        "[config]\n\"a\" = [\"x\", \"y\", \"z\"] # team\n"
+    )]
+    #[case::append_multiline(
+        "r = [\n    \"x\", # alice\n    \"y\", # bob\n]\n",
+        "r = [\"x\", \"y\", \"z\"]\n",
+        "r = [\n    \"x\", # alice\n    \"y\", # bob\n    \"z\",\n]\n"
+    )]
+    #[case::remove_multiline(
+        "r = [\n    \"x\", # alice\n    \"y\" # bob\n]\n",
+        "r = [\"y\"]\n",
+        "r = [\n    \"y\", # bob\n]\n"
+    )]
+    #[case::new_key(
+        "[config]\n# ops\n\"b\" = [\"x\"]\n",
+        "[config]\n\"a\" = [\"y\"]\n\"b\" = [\"x\"]\n",
+        "[config]\n# ops\n\"b\" = [\"x\"]\n\"a\" = [\"y\"]\n"
+    )]
+    #[case::changed_rule(
+        "version = 2\n\n# ops\n[[rules]]\npaths = [\"a\"]\nrecipients = [\"x\"] # alice\n\n# dev\n[[rules]]\npaths = [\"b\"]\nrecipients = [\"y\"]\n",
+        "version = 2\n\n[[rules]]\npaths = [\"c\"]\nrecipients = [\"z\"]\n\n[[rules]]\npaths = [\"a\"]\nrecipients = [\"x\", \"y\"]\n",
+        "version = 2\n\n[[rules]]\npaths = [\"c\"]\nrecipients = [\"z\"]\n\n# ops\n[[rules]]\npaths = [\"a\"]\nrecipients = [\"x\", \"y\"] # alice\n"
+    )]
+    #[case::split_rule(
+        "version = 2\n\n# ops\n[[rules]]\npaths = [\"a\", \"b\"] # shared\nrecipients = [\"x\"]\n",
+        "version = 2\n\n[[rules]]\npaths = [\"a\"]\nrecipients = [\"x\", \"y\"]\n\n[[rules]]\npaths = [\"b\"]\nrecipients = [\"x\"]\n",
+        "version = 2\n\n# ops\n[[rules]]\npaths = [\"a\"] # shared\nrecipients = [\"x\", \"y\"]\n\n[[rules]]\npaths = [\"b\"]\nrecipients = [\"x\"]\n"
+    )]
+    fn test_merge(#[case] document: &str, #[case] fresh: &str, #[case] expected: &str) {
+        let mut document: DocumentMut = document.parse().unwrap();
+        merge(&mut document, &fresh.parse().unwrap());
+        assert_eq!(document.to_string(), expected);
+    }
+}
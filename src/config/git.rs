@@ -32,6 +32,7 @@ where
 {
     ctx: &'a C,
     ns: String,
+    local: bool,
 }
 
 impl<'a, C> GitConfig<'a, C>
@@ -39,7 +40,21 @@ where
     C: Context,
 {
     pub fn new(ctx: &'a C, ns: String) -> Self {
-        Self { ctx, ns }
+        Self {
+            ctx,
+            ns,
+            local: false,
+        }
+    }
+
+    /// Entries only read from the config of the repository itself, for settings which must not
+    /// be injected through the global or system config
+    pub fn local(ctx: &'a C, ns: String) -> Self {
+        Self {
+            ctx,
+            ns,
+            local: true,
+        }
     }
 }
 
@@ -73,12 +88,12 @@ where
     }
 
     fn list(&self) -> Result<Vec<Self::Item>> {
-        Ok(self
-            .ctx
-            .repo()
-            .list_config(&self.ns)?
-            .into_iter()
-            .map(GitConfigEntry::new)
-            .collect())
+        let repo = self.ctx.repo();
+        let entries = if self.local {
+            repo.list_local_config(&format!("{}.{}", CONFIG_PATH, self.ns))?
+        } else {
+            repo.list_config(&self.ns)?
+        };
+        Ok(entries.into_iter().map(GitConfigEntry::new).collect())
     }
 }
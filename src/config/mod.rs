@@ -1,10 +1,17 @@
 mod age_identities;
 mod app;
+mod document;
 mod git;
+mod schema;
+mod trust;
 
 pub(crate) use age_identities::{AgeIdentities, AgeIdentity};
-pub(crate) use app::AppConfig;
+pub(crate) use app::{
+    find_recipients, AppConfig, CacheLocation, Compression, NoKeyPolicy, Padding, PlaintextPolicy,
+    Recipient, RecipientCheck, SignatureCheck,
+};
 pub(crate) use git::GitConfig;
+pub(crate) use trust::TrustedRecipients;
 
 use thiserror::Error;
 
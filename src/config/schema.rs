@@ -0,0 +1,384 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use toml_edit::{DocumentMut, Item};
+
+use super::{
+    app::{Compression, NoKeyPolicy, Padding, Recipient, Rule},
+    document,
+};
+
+/// Version of the configuration format written to new files
+pub(crate) const CURRENT_VERSION: u32 = 2;
+
+/// Contents of `git-agecrypt.toml`, independent of the version of its format
+pub(super) struct Contents {
+    pub(super) version: u32,
+    pub(super) escrow: Vec<Recipient>,
+    pub(super) default: Option<Rule>,
+    /// Named sets of recipients, assigned to paths by the `agecrypt-recipients` attribute
+    pub(super) groups: BTreeMap<String, Rule>,
+    pub(super) rules: Vec<Rule>,
+    /// File of the SSH keys allowed to sign ciphertexts, relative to the root of the repository
+    pub(super) allowed_signers: Option<PathBuf>,
+}
+
+impl Contents {
+    pub(super) fn parse(contents: &str) -> Result<Self> {
+        let Versioned { version } = toml::from_str(contents)?;
+        match version {
+            None => parse_v1(contents, 1),
+            Some(toml::Value::Integer(1)) => parse_v1(contents, 1),
+            Some(toml::Value::Integer(2)) => parse_v2(contents),
+            Some(toml::Value::Integer(version)) => bail!(
+                "Unsupported configuration version {}, this version of git-agecrypt supports \
+                 versions up to {}; upgrade git-agecrypt to use this repository",
+                version,
+                CURRENT_VERSION
+            ),
+            Some(version) => bail!("Invalid configuration version {}", version),
+        }
+    }
+
+    /// Updates `document`, the configuration file as it was loaded, to these contents
+    ///
+    /// Only the changed parts are rewritten and paths are kept sorted in version 1. Converting
+    /// to another version rewrites the whole file.
+    pub(super) fn update(&self, document: &mut DocumentMut) -> Result<()> {
+        let fresh = self.to_document()?;
+        let version = |document: &DocumentMut| document.get("version").and_then(Item::as_integer);
+        if version(document) != version(&fresh) {
+            *document = fresh;
+            return Ok(());
+        }
+        document::merge(document, &fresh);
+        if let Some(config) = document.get_mut("config").and_then(Item::as_table_mut) {
+            config.sort_values();
+        }
+        Ok(())
+    }
+
+    /// Serializes the contents, with rules as tables and everything nested in them inline
+    fn to_document(&self) -> Result<DocumentMut> {
+        let serialized = match self.version {
+            1 => to_toml_v1(self)?,
+            2 => to_toml_v2(self)?,
+            version => bail!("Unsupported configuration version {}", version),
+        };
+        let mut document: DocumentMut = serialized.parse()?;
+        for (key, item) in document.iter_mut() {
+            match item {
+                Item::Table(table) => table.iter_mut().for_each(|(_, item)| item.make_value()),
+                Item::ArrayOfTables(rules) if key.get() == "rules" => {
+                    for rule in rules.iter_mut() {
+                        rule.iter_mut().for_each(|(_, item)| item.make_value());
+                    }
+                }
+                item => item.make_value(),
+            }
+        }
+        Ok(document)
+    }
+}
+
+/// Only the version of the format, the rest of the file is parsed depending on it
+#[derive(Deserialize)]
+struct Versioned {
+    version: Option<toml::Value>,
+}
+
+/// Version 1: a map of paths to their recipients under `[config]`
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigV1 {
+    /// Already checked by [`Versioned`]
+    #[serde(default, rename = "version", skip_serializing)]
+    _version: Option<toml::Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    escrow: Vec<Recipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<RuleRepr>,
+    config: BTreeMap<PathBuf, RuleRepr>,
+    /// Only parsed to reject it
+    #[serde(default, skip_serializing)]
+    groups: Option<toml::Table>,
+    /// Only parsed to reject it
+    #[serde(default, skip_serializing)]
+    allowed_signers: Option<toml::Value>,
+}
+
+/// Version 2: an ordered list of rules, the first rule listing a path applies to it
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigV2 {
+    version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    escrow: Vec<Recipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_signers: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<DetailedRule>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, RuleRepr>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<RuleEntry>,
+}
+
+/// Serialized as a plain list of recipients unless recipient files or options are also given
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RuleRepr {
+    Recipients(Vec<Recipient>),
+    Detailed(DetailedRule),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DetailedRule {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<Recipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipients_file: Option<OneOrMany>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    no_key: Option<NoKeyPolicy>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    armor: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compress: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    padding: Option<Padding>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    paths: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<Recipient>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipients_file: Option<OneOrMany>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    no_key: Option<NoKeyPolicy>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    armor: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compress: Option<Compression>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    padding: Option<Padding>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn from_one_or_many(files: Option<OneOrMany>) -> Vec<PathBuf> {
+    match files {
+        Some(OneOrMany::One(file)) => vec![file],
+        Some(OneOrMany::Many(files)) => files,
+        None => vec![],
+    }
+}
+
+fn to_one_or_many(files: &[PathBuf]) -> Option<OneOrMany> {
+    match files {
+        [] => None,
+        [file] => Some(OneOrMany::One(file.clone())),
+        files => Some(OneOrMany::Many(files.to_vec())),
+    }
+}
+
+impl DetailedRule {
+    fn into_rule(self, paths: Vec<PathBuf>) -> Rule {
+        Rule {
+            paths,
+            recipients: self.recipients,
+            recipients_file: from_one_or_many(self.recipients_file),
+            no_key: self.no_key,
+            armor: self.armor,
+            compress: self.compress,
+            padding: self.padding,
+        }
+    }
+}
+
+impl From<&Rule> for DetailedRule {
+    fn from(rule: &Rule) -> Self {
+        Self {
+            recipients: rule.recipients.clone(),
+            recipients_file: to_one_or_many(&rule.recipients_file),
+            no_key: rule.no_key,
+            armor: rule.armor,
+            compress: rule.compress,
+            padding: rule.padding,
+        }
+    }
+}
+
+impl RuleRepr {
+    fn into_rule(self, paths: Vec<PathBuf>) -> Rule {
+        match self {
+            Self::Recipients(recipients) => Rule {
+                paths,
+                recipients,
+                ..Default::default()
+            },
+            Self::Detailed(rule) => rule.into_rule(paths),
+        }
+    }
+}
+
+impl From<&Rule> for RuleRepr {
+    fn from(rule: &Rule) -> Self {
+        if rule.recipients_file.is_empty()
+            && rule.no_key.is_none()
+            && !rule.armor
+            && rule.compress.is_none()
+            && rule.padding.is_none()
+        {
+            Self::Recipients(rule.recipients.clone())
+        } else {
+            Self::Detailed(rule.into())
+        }
+    }
+}
+
+impl From<RuleEntry> for Rule {
+    fn from(entry: RuleEntry) -> Self {
+        Self {
+            paths: entry.paths,
+            recipients: entry.recipients,
+            recipients_file: from_one_or_many(entry.recipients_file),
+            no_key: entry.no_key,
+            armor: entry.armor,
+            compress: entry.compress,
+            padding: entry.padding,
+        }
+    }
+}
+
+impl From<&Rule> for RuleEntry {
+    fn from(rule: &Rule) -> Self {
+        Self {
+            paths: rule.paths.clone(),
+            recipients: rule.recipients.clone(),
+            recipients_file: to_one_or_many(&rule.recipients_file),
+            no_key: rule.no_key,
+            armor: rule.armor,
+            compress: rule.compress,
+            padding: rule.padding,
+        }
+    }
+}
+
+/// Options which can't be represented in version 1, they would be lost when saving the file
+fn ensure_v1<'a>(
+    rules: impl Iterator<Item = &'a Rule>,
+    has_groups: bool,
+    has_signers: bool,
+) -> Result<()> {
+    let rules: Vec<_> = rules.collect();
+    let feature = if rules.iter().any(|rule| rule.armor) {
+        "The 'armor' option requires"
+    } else if rules.iter().any(|rule| rule.compress.is_some()) {
+        "The 'compress' option requires"
+    } else if rules.iter().any(|rule| rule.padding.is_some()) {
+        "The 'padding' option requires"
+    } else if has_groups {
+        "Recipient groups require"
+    } else if has_signers {
+        "Signed ciphertexts require"
+    } else {
+        return Ok(());
+    };
+    bail!(
+        "{} configuration version 2, run `git-agecrypt config migrate` first",
+        feature
+    );
+}
+
+fn parse_v1(contents: &str, version: u32) -> Result<Contents> {
+    let config: ConfigV1 = toml::from_str(contents)?;
+    let rules: Vec<Rule> = config
+        .config
+        .into_iter()
+        .map(|(path, rule)| rule.into_rule(vec![path]))
+        .collect();
+    let default = config.default.map(|rule| rule.into_rule(vec![]));
+    ensure_v1(
+        rules.iter().chain(&default),
+        config.groups.is_some(),
+        config.allowed_signers.is_some(),
+    )?;
+    Ok(Contents {
+        version,
+        escrow: config.escrow,
+        default,
+        groups: BTreeMap::new(),
+        rules,
+        allowed_signers: None,
+    })
+}
+
+fn parse_v2(contents: &str) -> Result<Contents> {
+    let config: ConfigV2 = toml::from_str(contents)?;
+    Ok(Contents {
+        version: config.version,
+        escrow: config.escrow,
+        default: config.default.map(|rule| rule.into_rule(vec![])),
+        groups: config
+            .groups
+            .into_iter()
+            .map(|(name, group)| (name, group.into_rule(vec![])))
+            .collect(),
+        rules: config.rules.into_iter().map(Rule::from).collect(),
+        allowed_signers: config.allowed_signers,
+    })
+}
+
+fn to_toml_v1(contents: &Contents) -> Result<String> {
+    ensure_v1(
+        contents.rules.iter().chain(&contents.default),
+        !contents.groups.is_empty(),
+        contents.allowed_signers.is_some(),
+    )?;
+    let mut config = BTreeMap::new();
+    for rule in &contents.rules {
+        for path in &rule.paths {
+            // Only the first rule of a path applies to it
+            config.entry(path.clone()).or_insert_with(|| rule.into());
+        }
+    }
+    let config = ConfigV1 {
+        _version: None,
+        escrow: contents.escrow.clone(),
+        default: contents.default.as_ref().map(RuleRepr::from),
+        config,
+        groups: None,
+        allowed_signers: None,
+    };
+    toml::to_string(&config).context("Couldn't format configuration as TOML")
+}
+
+fn to_toml_v2(contents: &Contents) -> Result<String> {
+    let config = ConfigV2 {
+        version: contents.version,
+        escrow: contents.escrow.clone(),
+        allowed_signers: contents.allowed_signers.clone(),
+        default: contents.default.as_ref().map(DetailedRule::from),
+        groups: contents
+            .groups
+            .iter()
+            .map(|(name, group)| (name.clone(), group.into()))
+            .collect(),
+        rules: contents.rules.iter().map(RuleEntry::from).collect(),
+    };
+    toml::to_string(&config).context("Couldn't format configuration as TOML")
+}
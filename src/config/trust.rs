@@ -0,0 +1,51 @@
+use anyhow::Context as AnyhowContext;
+
+use crate::age;
+
+use super::{app::Recipient, git::GitConfigEntry, Container, Result};
+
+/// Recipients approved for encryption in this checkout, stored in `.git/config`
+///
+/// Only the keys are stored, without SSH key comments, so that a recipient can't be replaced by
+/// another one with the same label.
+pub(crate) struct TrustedRecipients<C>(pub C)
+where
+    C: Container<Item = GitConfigEntry>;
+
+impl<C> Container for TrustedRecipients<C>
+where
+    C: Container<Item = GitConfigEntry>,
+{
+    type Item = Recipient;
+
+    fn add(&mut self, recipient: Self::Item) -> Result<()> {
+        age::validate_public_keys(&[recipient.key()])
+            .with_context(|| format!("Can't trust invalid recipient {}", recipient))?;
+        let key = age::recipient_key(recipient.key()).to_string();
+        self.0.add(key.into())?;
+        Ok(())
+    }
+
+    fn remove(&mut self, recipient: Self::Item) -> Result<()> {
+        let key = age::recipient_key(recipient.key()).to_string();
+        self.0.remove(key.into())?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<Self::Item>> {
+        let keys = self.0.list()?;
+        Ok(keys
+            .into_iter()
+            .map(|key| Recipient::new(key.into(), None))
+            .collect())
+    }
+}
+
+impl<C> TrustedRecipients<C>
+where
+    C: Container<Item = GitConfigEntry>,
+{
+    pub fn new(cfg: C) -> Self {
+        Self(cfg)
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::Result;
+
+use crate::{
+    age,
+    config::{
+        AgeIdentities, AgeIdentity, AppConfig, Container, GitConfig, Recipient, TrustedRecipients,
+    },
+    git::{self, Repository},
+};
+
+use super::{Context, SessionCache};
+
+/// A [`Context`] backed by a [`git::MemoryRepository`], keeping sidecar files
+/// and the contents of `git-agecrypt.toml` in memory.
+pub(crate) struct MemoryContext {
+    repo: git::MemoryRepository,
+    sidecars: RefCell<HashMap<(PathBuf, String), Vec<u8>>>,
+    sidecar_key: [u8; 32],
+    config: RefCell<String>,
+    session: SessionCache,
+}
+
+impl MemoryContext {
+    pub(crate) fn new(repo: git::MemoryRepository) -> Self {
+        Self {
+            repo,
+            sidecars: Default::default(),
+            sidecar_key: rand::random(),
+            config: RefCell::new("[config]\n".into()),
+            session: SessionCache::default(),
+        }
+    }
+
+    pub(crate) fn set_config_contents(&self, contents: &str) {
+        *self.config.borrow_mut() = contents.into();
+    }
+
+    pub(crate) fn sidecar_count(&self) -> usize {
+        self.sidecars.borrow().len()
+    }
+
+    fn sidecar_key(&self, path: &Path, extension: &str) -> Result<(PathBuf, String)> {
+        let relpath = path.strip_prefix(self.repo.workdir())?;
+        Ok((relpath.into(), extension.into()))
+    }
+}
+
+impl Context for MemoryContext {
+    type Repo = git::MemoryRepository;
+
+    fn repo(&self) -> &Self::Repo {
+        &self.repo
+    }
+
+    fn store_sidecar(&self, for_path: &Path, extension: &str, content: &[u8]) -> Result<()> {
+        let key = self.sidecar_key(for_path, extension)?;
+        self.sidecars.borrow_mut().insert(key, content.into());
+        Ok(())
+    }
+
+    fn load_sidecar(&self, for_path: &Path, extension: &str) -> Result<Option<Vec<u8>>> {
+        let key = self.sidecar_key(for_path, extension)?;
+        Ok(self.sidecars.borrow().get(&key).cloned())
+    }
+
+    fn sidecar_key(&self) -> Result<[u8; 32]> {
+        Ok(self.sidecar_key)
+    }
+
+    fn current_exe(&self) -> Result<String> {
+        Ok("git-agecrypt".into())
+    }
+
+    fn remove_sidecar_files(&self) -> Result<()> {
+        self.sidecars.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn age_identities(&self) -> Box<dyn Container<Item = AgeIdentity> + '_> {
+        let cfg = GitConfig::new(self, "identity".into());
+        Box::new(AgeIdentities::new(cfg))
+    }
+
+    fn trusted_recipients(&self) -> Box<dyn Container<Item = Recipient> + '_> {
+        let cfg = GitConfig::local(self, "trusted".into());
+        Box::new(TrustedRecipients::new(cfg))
+    }
+
+    fn age_session(&self) -> Result<Rc<age::Session>> {
+        let identities = self.age_identities().list()?;
+        self.session
+            .get(identities.into_iter().map(|i| i.path).collect())
+    }
+
+    fn config(&self) -> Result<AppConfig> {
+        Ok(AppConfig::parse(
+            &self.config.borrow(),
+            &PathBuf::from("git-agecrypt.toml"),
+            self.repo.workdir(),
+        )?)
+    }
+
+    fn save_config(&self, cfg: &AppConfig) -> Result<()> {
+        *self.config.borrow_mut() = cfg.to_toml()?;
+        Ok(())
+    }
+}
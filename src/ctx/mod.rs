@@ -0,0 +1,418 @@
+use std::{
+    cell::RefCell,
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Context as _, Result};
+
+use crate::{
+    age,
+    config::{
+        AgeIdentities, AgeIdentity, AppConfig, CacheLocation, Container, GitConfig, Recipient,
+        TrustedRecipients,
+    },
+    git,
+};
+
+/// Git config setting where the sidecar files are kept
+const CACHE_CONFIG: &str = "git-agecrypt.config.cache";
+
+#[cfg(test)]
+mod memory;
+
+#[cfg(test)]
+pub(crate) use memory::MemoryContext;
+
+pub(crate) trait Context {
+    type Repo: git::Repository;
+
+    fn repo(&self) -> &Self::Repo;
+
+    fn store_sidecar(&self, for_path: &Path, extension: &str, content: &[u8]) -> Result<()>;
+
+    fn load_sidecar(
+        &self,
+        for_path: &Path,
+        extension: &str,
+    ) -> Result<Option<Vec<u8>>>;
+
+    /// Key of the hashes of plaintexts stored in the sidecar files, generated on first use
+    ///
+    /// Unkeyed hashes would allow guessing low-entropy secrets, e.g. PINs, from the cache.
+    fn sidecar_key(&self) -> Result<[u8; 32]>;
+
+    fn current_exe(&self) -> Result<String>;
+
+    fn remove_sidecar_files(&self) -> Result<()>;
+
+    fn age_identities(&self) -> Box<dyn Container<Item = AgeIdentity> + '_>;
+
+    /// Recipients approved for encryption in this checkout
+    fn trusted_recipients(&self) -> Box<dyn Container<Item = Recipient> + '_>;
+
+    /// Identities loaded once for all files encrypted and decrypted by the command
+    fn age_session(&self) -> Result<Rc<age::Session>>;
+
+    fn config(&self) -> Result<AppConfig>;
+
+    fn save_config(&self, cfg: &AppConfig) -> Result<()>;
+}
+
+/// The [`age::Session`] of a command, loaded on first use and again when the configured
+/// identities change
+#[derive(Default)]
+pub(crate) struct SessionCache(RefCell<Option<(Vec<String>, Rc<age::Session>)>>);
+
+impl SessionCache {
+    pub(crate) fn get(&self, identities: Vec<String>) -> Result<Rc<age::Session>> {
+        let mut cached = self.0.borrow_mut();
+        if let Some((loaded, session)) = &*cached {
+            if *loaded == identities {
+                return Ok(session.clone());
+            }
+        }
+        let session = Rc::new(age::Session::new(&identities)?);
+        *cached = Some((identities, session.clone()));
+        Ok(session)
+    }
+}
+
+struct ContextWrapper<R: git::Repository> {
+    repo: R,
+    session: SessionCache,
+    /// `$XDG_CACHE_HOME`, `None` when neither it nor `$HOME` is set
+    cache_home: Option<PathBuf>,
+}
+
+impl<R: git::Repository> ContextWrapper<R> {
+    pub(crate) fn new(repo: R) -> Self {
+        Self {
+            repo,
+            session: SessionCache::default(),
+            cache_home: cache_home(),
+        }
+    }
+    fn sidecar_directory(&self) -> Result<PathBuf> {
+        let location = match self.repo.get_config(CACHE_CONFIG) {
+            Ok(value) => value
+                .parse()
+                .with_context(|| format!("Invalid '{}' setting", CACHE_CONFIG))?,
+            Err(git::Error::NotExist(_)) => CacheLocation::default(),
+            Err(e) => return Err(e.into()),
+        };
+        match location {
+            CacheLocation::Git => Ok(self.repo.path().join("git-agecrypt")),
+            CacheLocation::Xdg => self.xdg_cache_directory(),
+        }
+    }
+
+    /// Directory of this repository under `$XDG_CACHE_HOME`, named after the path of the
+    /// repository
+    fn xdg_cache_directory(&self) -> Result<PathBuf> {
+        let cache_home = self
+            .cache_home
+            .as_ref()
+            .ok_or_else(|| anyhow!("Neither XDG_CACHE_HOME nor HOME is set"))?;
+        let repo = fs::canonicalize(self.repo.path())?;
+        let id = blake3::hash(repo.to_string_lossy().as_bytes()).to_hex();
+        Ok(cache_home.join("git-agecrypt").join(&id[..32]))
+    }
+
+    fn sidecar_key_path(&self) -> PathBuf {
+        self.repo.path().join("git-agecrypt.key")
+    }
+
+    /// Removes the hashes in the repository from before they were keyed, which would allow
+    /// guessing short plaintexts
+    fn remove_unkeyed_hashes(&self) -> Result<()> {
+        let entries = match fs::read_dir(self.repo.path().join("git-agecrypt")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "hash") {
+                fs::remove_file(path).or_else(ignore_not_found)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_sidecar(&self, path: &Path, extension: &str) -> Result<PathBuf> {
+        let relpath = path.strip_prefix(self.repo.workdir())?;
+        let name = relpath.to_string_lossy().replace('/', "!");
+
+        let dir = self.sidecar_directory()?;
+        let moved = !dir.is_dir() && !dir.starts_with(self.repo.path());
+        create_private_dir(&dir)?;
+        if moved {
+            // The cache moved out of the repository, the old one is removed once, along with
+            // hashes from before they were keyed
+            fs::remove_dir_all(self.repo.path().join("git-agecrypt")).or_else(ignore_not_found)?;
+        }
+
+        let mut rv = dir.join(name);
+        rv.set_extension(extension);
+        Ok(rv)
+    }
+}
+
+impl<R: git::Repository> Context for ContextWrapper<R> {
+    type Repo = R;
+    fn repo(&self) -> &R {
+        &self.repo
+    }
+
+    fn store_sidecar(&self, for_path: &Path, extension: &str, content: &[u8]) -> Result<()> {
+        let sidecar_path = self.get_sidecar(for_path, extension)?;
+        write_private(&sidecar_path, content)?;
+        Ok(())
+    }
+
+    fn load_sidecar(
+        &self,
+        for_path: &Path,
+        extension: &str,
+    ) -> Result<Option<Vec<u8>>> {
+        let sidecar_path = self.get_sidecar(for_path, extension)?;
+        match File::open(sidecar_path) {
+            Ok(mut f) => {
+                let mut buff = Vec::new();
+                f.read_to_end(&mut buff)?;
+                Ok(Some(buff))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => {
+                bail!(e)
+            }
+        }
+    }
+
+    fn sidecar_key(&self) -> Result<[u8; 32]> {
+        let path = self.sidecar_key_path();
+        match fs::read(&path) {
+            Ok(key) => {
+                return key.try_into().map_err(|_| {
+                    anyhow!(
+                        "Invalid key of the sidecar files in '{}', remove it to generate a new one",
+                        path.display()
+                    )
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let key: [u8; 32] = rand::random();
+        // Created only readable by the owner and moved in place at once, a concurrent filter
+        // process may have created it in the meantime
+        let mut file = tempfile::NamedTempFile::new_in(self.repo.path())?;
+        file.write_all(&key)?;
+        match file.persist_noclobber(&path) {
+            Ok(_) => {
+                self.remove_unkeyed_hashes()?;
+                Ok(key)
+            }
+            Err(e) if e.error.kind() == io::ErrorKind::AlreadyExists => self.sidecar_key(),
+            Err(e) => Err(e.error.into()),
+        }
+    }
+
+    fn current_exe(&self) -> Result<String> {
+        let exe = std::env::current_exe()?;
+        let exe = exe.to_string_lossy();
+        Ok(exe.into())
+    }
+
+    fn remove_sidecar_files(&self) -> Result<()> {
+        // Both locations, in case the setting was changed
+        let mut dirs = vec![self.repo.path().join("git-agecrypt")];
+        dirs.extend(self.xdg_cache_directory().ok());
+        for dir in dirs {
+            fs::remove_dir_all(dir).or_else(ignore_not_found)?;
+        }
+        fs::remove_file(self.sidecar_key_path()).or_else(ignore_not_found)?;
+        Ok(())
+    }
+
+    fn age_identities(&self) -> Box<dyn Container<Item = AgeIdentity> + '_> {
+        let cfg = GitConfig::new(self, "identity".into());
+        Box::new(AgeIdentities::new(cfg))
+    }
+
+    fn trusted_recipients(&self) -> Box<dyn Container<Item = Recipient> + '_> {
+        let cfg = GitConfig::local(self, "trusted".into());
+        Box::new(TrustedRecipients::new(cfg))
+    }
+
+    fn age_session(&self) -> Result<Rc<age::Session>> {
+        let identities = self.age_identities().list()?;
+        self.session
+            .get(identities.into_iter().map(|i| i.path).collect())
+    }
+
+    fn config(&self) -> Result<AppConfig> {
+        Ok(AppConfig::load(
+            &self.repo.workdir().join("git-agecrypt.toml"),
+            self.repo.workdir(),
+        )?)
+    }
+
+    fn save_config(&self, cfg: &AppConfig) -> Result<()> {
+        Ok(cfg.save()?)
+    }
+}
+
+pub(crate) fn new<R: git::Repository>(repo: R) -> impl Context<Repo = R> {
+    ContextWrapper::new(repo)
+}
+
+fn cache_home() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| Path::new(&home).join(".cache")),
+    }
+}
+
+fn ignore_not_found(err: io::Error) -> io::Result<()> {
+    if err.kind() == io::ErrorKind::NotFound {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Creates `dir` only accessible by the owner, also restricting it if it already exists
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        // Created with the default permissions by earlier versions
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+    }
+    #[cfg(not(unix))]
+    fs::create_dir_all(dir)
+}
+
+/// Writes `contents` to `path`, only readable by the owner
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        let file = options.open(path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        (&file).write_all(contents)
+    }
+    #[cfg(not(unix))]
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+
+    use super::*;
+    use crate::git::{MemoryRepository, Repository};
+
+    #[test]
+    fn test_sidecar_files() -> Result<()> {
+        let dir = TempDir::new()?;
+        dir.child(".git").create_dir_all()?;
+        let ctx = new(MemoryRepository::new(dir.path()));
+        let file = dir.join("secrets/secret.txt");
+
+        ctx.store_sidecar(&file, "age", b"ciphertext")?;
+        assert_eq!(
+            ctx.load_sidecar(&file, "age")?,
+            Some(b"ciphertext".to_vec())
+        );
+        dir.child(".git/git-agecrypt/secrets!secret.age")
+            .assert("ciphertext");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = |path: &str| fs::metadata(dir.join(path)).unwrap().permissions().mode();
+            assert_eq!(mode(".git/git-agecrypt") & 0o777, 0o700);
+            assert_eq!(mode(".git/git-agecrypt/secrets!secret.age") & 0o777, 0o600);
+        }
+
+        // The key is kept for the following filter processes
+        let key = ctx.sidecar_key()?;
+        assert_eq!(new(MemoryRepository::new(dir.path())).sidecar_key()?, key);
+
+        ctx.repo().set_config(CACHE_CONFIG, "nowhere")?;
+        assert!(ctx.load_sidecar(&file, "age").is_err());
+
+        ctx.remove_sidecar_files()?;
+        assert!(!dir.join(".git/git-agecrypt").exists());
+        assert!(!dir.join(".git/git-agecrypt.key").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unkeyed_hashes_are_removed() -> Result<()> {
+        let dir = TempDir::new()?;
+        dir.child(".git/git-agecrypt/secret.hash")
+            .write_str("unkeyed")?;
+        dir.child(".git/git-agecrypt/secret.age")
+            .write_str("ciphertext")?;
+        let ctx = new(MemoryRepository::new(dir.path()));
+        let file = dir.join("secret");
+
+        // Hashes from before the key was created can't be trusted
+        ctx.sidecar_key()?;
+        assert_eq!(ctx.load_sidecar(&file, "hash")?, None);
+        assert_eq!(
+            ctx.load_sidecar(&file, "age")?,
+            Some(b"ciphertext".to_vec())
+        );
+        ctx.store_sidecar(&file, "hash", b"keyed")?;
+        ctx.sidecar_key()?;
+        assert_eq!(ctx.load_sidecar(&file, "hash")?, Some(b"keyed".to_vec()));
+
+        // Nothing is left behind when the cache moves out of the repository
+        let cache_home = TempDir::new()?;
+        let ctx = ContextWrapper {
+            cache_home: Some(cache_home.to_path_buf()),
+            ..ContextWrapper::new(MemoryRepository::new(dir.path()))
+        };
+        ctx.repo().set_config(CACHE_CONFIG, "xdg")?;
+        assert_eq!(ctx.load_sidecar(&file, "hash")?, None);
+        assert!(!dir.join(".git/git-agecrypt").exists());
+        ctx.store_sidecar(&file, "hash", b"keyed")?;
+
+        // Only once, a cache in the repository isn't removed on each access
+        dir.child(".git/git-agecrypt").create_dir_all()?;
+        assert_eq!(ctx.load_sidecar(&file, "hash")?, Some(b"keyed".to_vec()));
+        assert!(dir.join(".git/git-agecrypt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_age_session_is_shared() -> Result<()> {
+        let dir = TempDir::new()?;
+        let ctx = new(MemoryRepository::new(dir.path()));
+        let session = ctx.age_session()?;
+        assert!(Rc::ptr_eq(&ctx.age_session()?, &session));
+
+        // Loaded again with the new identities
+        ctx.repo()
+            .add_config("git-agecrypt.config.identity", "identity.txt")?;
+        assert!(!Rc::ptr_eq(&ctx.age_session()?, &session));
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+//! Framing of plaintexts inside the encryption
+//!
+//! Compressed or padded files are framed by a header of a magic number, the version of the
+//! format, the compression of the contents and their length, followed by the contents and
+//! zeros up to the padded size. Other files are encrypted as they are, so plaintexts without
+//! the header are the contents themselves, as written by earlier versions of git-agecrypt.
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    config::{Compression, Padding},
+    secret::{self, SecretBytes},
+};
+
+const MAGIC: &[u8] = b"\0git-agecrypt\0";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2 + 8;
+
+/// Identifiers of the compressions in the header
+const UNCOMPRESSED: u8 = 0;
+const ZSTD: u8 = 1;
+
+/// Frames `contents` compressed and padded according to the options, returns them as they are
+/// without options
+pub(crate) fn wrap(
+    contents: SecretBytes,
+    compress: Option<Compression>,
+    padding: Option<Padding>,
+) -> Result<SecretBytes> {
+    // Contents starting with the magic number are framed anyway, so that they can't be
+    // mistaken for a frame
+    if compress.is_none() && padding.is_none() && !contents.starts_with(MAGIC) {
+        return Ok(contents);
+    }
+    let compressed;
+    let (encoding, contents) = match compress {
+        None => (UNCOMPRESSED, &contents[..]),
+        Some(Compression::Zstd) => {
+            compressed = secret::read_to_end(zstd::stream::read::Encoder::new(&contents[..], 0)?)?;
+            (ZSTD, &compressed[..])
+        }
+    };
+    let framed = (HEADER_LEN + contents.len()) as u64;
+    let size = match padding {
+        None => framed,
+        Some(Padding::PowerOfTwo) => framed.next_power_of_two(),
+        Some(Padding::Bucket(0) | Padding::Size(0)) => bail!("Invalid padding of 0 bytes"),
+        Some(Padding::Bucket(bucket)) => framed.div_ceil(bucket) * bucket,
+        Some(Padding::Size(size)) if size < framed => bail!(
+            "File of {} bytes doesn't fit in the padding of {} bytes",
+            contents.len(),
+            size
+        ),
+        Some(Padding::Size(size)) => size,
+    };
+
+    let mut rv = SecretBytes::new(Vec::with_capacity(size as usize));
+    rv.extend_from_slice(MAGIC);
+    rv.push(VERSION);
+    rv.push(encoding);
+    rv.extend_from_slice(&(contents.len() as u64).to_be_bytes());
+    rv.extend_from_slice(contents);
+    rv.resize(size as usize, 0);
+    Ok(rv)
+}
+
+/// Returns the contents of the `plaintext` of a file, without its frame and padding and
+/// decompressed
+pub(crate) fn unwrap(mut plaintext: SecretBytes) -> Result<SecretBytes> {
+    if !plaintext.starts_with(MAGIC) {
+        return Ok(plaintext);
+    }
+    let Some(header) = plaintext.get(..HEADER_LEN) else {
+        bail!("Truncated frame of encrypted file");
+    };
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        bail!(
+            "Unsupported frame version {} of encrypted file, upgrade git-agecrypt to decrypt it",
+            version
+        );
+    }
+    let encoding = header[MAGIC.len() + 1];
+    let len = u64::from_be_bytes(header[MAGIC.len() + 2..].try_into()?);
+    match usize::try_from(len) {
+        Ok(len) if len <= plaintext.len() - HEADER_LEN => {
+            plaintext.truncate(HEADER_LEN + len);
+            plaintext.drain(..HEADER_LEN);
+        }
+        _ => bail!("Truncated frame of encrypted file"),
+    }
+    match encoding {
+        UNCOMPRESSED => Ok(plaintext),
+        ZSTD => zstd::stream::read::Decoder::new(&plaintext[..])
+            .and_then(secret::read_to_end)
+            .context("Couldn't decompress encrypted file"),
+        _ => bail!(
+            "Unsupported compression {} of encrypted file, upgrade git-agecrypt to decrypt it",
+            encoding
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use rstest::rstest;
+
+    use super::*;
+
+    fn bytes(contents: &[u8]) -> SecretBytes {
+        SecretBytes::new(contents.to_vec())
+    }
+
+    #[rstest]
+    #[case::none(None, 6)]
+    #[case::power_of_two(Some(Padding::PowerOfTwo), 32)]
+    #[case::bucket(Some(Padding::Bucket(100)), 100)]
+    #[case::size(Some(Padding::Size(1024)), 1024)]
+    fn test_wrap_unwrap(#[case] padding: Option<Padding>, #[case] size: usize) -> Result<()> {
+        let framed = wrap(bytes(b"secret"), None, padding)?;
+        assert_eq!(framed.len(), size);
+        assert_eq!(*unwrap(framed)?, b"secret");
+        Ok(())
+    }
+
+    #[rstest]
+    #[case::unpadded(None)]
+    #[case::padded(Some(Padding::Bucket(1024)))]
+    fn test_compressed(#[case] padding: Option<Padding>) -> Result<()> {
+        let contents = b"INSERT INTO secrets VALUES ('secret');\n".repeat(100);
+        let framed = wrap(bytes(&contents), Some(Compression::Zstd), padding)?;
+        assert!(framed.len() < contents.len());
+        assert_eq!(*unwrap(framed)?, contents);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap_too_large() {
+        assert_matches!(
+            wrap(bytes(&[1; 1024]), None, Some(Padding::Size(1024))),
+            Err(_)
+        );
+        assert_matches!(
+            wrap(bytes(b"secret"), None, Some(Padding::Bucket(0))),
+            Err(_)
+        );
+    }
+
+    #[test]
+    fn test_unwrap_unframed() -> Result<()> {
+        let lookalike = [MAGIC, b"contents"].concat();
+        let framed = wrap(bytes(&lookalike), None, None)?;
+        assert_ne!(*framed, lookalike);
+        assert_eq!(*unwrap(framed)?, lookalike);
+
+        let mut future = wrap(bytes(b"secret"), None, Some(Padding::PowerOfTwo))?;
+        future[MAGIC.len()] = VERSION + 1;
+        assert_matches!(unwrap(future), Err(_));
+        let mut future = wrap(bytes(b"secret"), None, Some(Padding::PowerOfTwo))?;
+        future[MAGIC.len() + 1] = ZSTD + 1;
+        assert_matches!(unwrap(future), Err(_));
+        assert_matches!(unwrap(bytes(MAGIC)), Err(_));
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use std::{
+    cell::{Ref, RefCell},
+    env,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use gix::bstr::{BStr, ByteSlice};
+use regex::Regex;
+
+use super::{Error, Repository, Result};
+
+pub(crate) struct GixRepository {
+    inner: RefCell<gix::Repository>,
+    workdir: PathBuf,
+    path: PathBuf,
+}
+
+impl GixRepository {
+    pub(crate) fn from_current_dir() -> Result<Self> {
+        Self::from_dir(env::current_dir().context("Cannot determine current directory")?)
+    }
+
+    pub(crate) fn from_dir(path: PathBuf) -> Result<Self> {
+        let inner = gix::discover(&path)
+            .with_context(|| format!("'{}' Not a git repository", path.display()))?;
+        let workdir = inner
+            .work_dir()
+            .ok_or_else(|| anyhow!("Bare repositories are unsupported {}", path.display()))?
+            .into();
+        let path = inner.git_dir().into();
+        Ok(Self {
+            inner: RefCell::new(inner),
+            workdir,
+            path,
+        })
+    }
+
+    fn repo(&self) -> Ref<'_, gix::Repository> {
+        self.inner.borrow()
+    }
+
+    fn relpath<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        Ok(path.strip_prefix(&self.workdir).with_context(|| {
+            format!(
+                "Path {} is outside of git repository {}",
+                path.display(),
+                self.workdir.display()
+            )
+        })?)
+    }
+
+    /// Applies `edit` to the repository local configuration file, then reloads the repository
+    /// to pick up the changes.
+    ///
+    /// Like git, the file is locked through `config.lock` while it is edited, and the lock file
+    /// replaces it once written.
+    fn edit_local_config<T>(
+        &self,
+        edit: impl FnOnce(&mut gix::config::File<'static>) -> Result<T>,
+    ) -> Result<T> {
+        let config_path = self.repo().common_dir().join("config");
+        let mut lock = gix::lock::File::acquire_to_update_resource(
+            &config_path,
+            gix::lock::acquire::Fail::Immediately,
+            None,
+        )
+        .with_context(|| format!("Couldn't lock '{}'", config_path.display()))?;
+        let mut file = gix::config::File::from_path_no_includes(
+            config_path.clone(),
+            gix::config::Source::Local,
+        )
+        .with_context(|| format!("Couldn't load '{}'", config_path.display()))?;
+        let rv = edit(&mut file)?;
+        file.write_to(&mut lock)
+            .with_context(|| format!("Couldn't save '{}'", config_path.display()))?;
+        lock.commit()
+            .map_err(|e| e.error)
+            .with_context(|| format!("Couldn't save '{}'", config_path.display()))?;
+
+        let reloaded = gix::open(&self.path).context("Couldn't reload repository")?;
+        *self.inner.borrow_mut() = reloaded;
+        Ok(rv)
+    }
+}
+
+/// A config key split into its `section`, optional `subsection` and `name` components
+struct Key<'a> {
+    section: &'a str,
+    subsection: Option<&'a BStr>,
+    name: &'a str,
+}
+
+impl<'a> Key<'a> {
+    fn parse(key: &'a str) -> Result<Self> {
+        let (section, rest) = key
+            .split_once('.')
+            .ok_or_else(|| anyhow!("Invalid config key '{}'", key))?;
+        let (subsection, name) = match rest.rsplit_once('.') {
+            Some((subsection, name)) => (Some(subsection.into()), name),
+            None => (None, rest),
+        };
+        if section.is_empty() || name.is_empty() {
+            return Err(anyhow!("Invalid config key '{}'", key).into());
+        }
+        Ok(Self {
+            section,
+            subsection,
+            name,
+        })
+    }
+
+    fn value_name(&self) -> Result<gix::config::parse::section::Key<'static>> {
+        let name: gix::config::parse::section::Key<'_> = self
+            .name
+            .try_into()
+            .with_context(|| format!("Invalid config variable name '{}'", self.name))?;
+        Ok(name.to_owned())
+    }
+}
+
+fn full_key(section: &gix::config::file::Section<'_>, name: &str) -> String {
+    let header = section.header();
+    match header.subsection_name() {
+        Some(subsection) => format!(
+            "{}.{}.{}",
+            header.name().to_str_lossy().to_lowercase(),
+            subsection,
+            name.to_lowercase()
+        ),
+        None => format!(
+            "{}.{}",
+            header.name().to_str_lossy().to_lowercase(),
+            name.to_lowercase()
+        ),
+    }
+}
+
+impl Repository for GixRepository {
+    fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relpath(path)?;
+        let repo = self.repo();
+        if repo
+            .head()
+            .context("Couldn not determine repository head")?
+            .is_unborn()
+        {
+            return Err(Error::NotExist("Repository has no commits yet".into()));
+        }
+        let tree = repo
+            .head_commit()
+            .context("Couldn not determine repository head")?
+            .tree()
+            .map_err(|e| anyhow!(e))?;
+        let mut buf = vec![];
+        let entry = tree
+            .lookup_entry_by_path(relpath, &mut buf)
+            .map_err(|e| anyhow!(e))?
+            .ok_or_else(|| {
+                Error::NotExist(format!("Path {} is not found in HEAD", relpath.display()))
+            })?;
+        let object = entry.object().map_err(|e| anyhow!(e))?;
+        Ok(object.detach().data)
+    }
+
+    fn get_index_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relpath(path)?;
+        let repo = self.repo();
+        let index = repo.index_or_empty().map_err(|e| anyhow!(e))?;
+        let entry = index
+            .entry_by_path_and_stage(
+                gix::path::into_bstr(relpath).as_ref(),
+                gix::index::entry::Stage::Unconflicted,
+            )
+            .ok_or_else(|| {
+                Error::NotExist(format!("Path {} is not found in index", relpath.display()))
+            })?;
+        let object = repo.find_object(entry.id).map_err(|e| anyhow!(e))?;
+        Ok(object.detach().data)
+    }
+
+    fn index_paths(&self) -> Result<Vec<PathBuf>> {
+        let repo = self.repo();
+        let index = repo.index_or_empty().map_err(|e| anyhow!(e))?;
+        let mut paths: Vec<PathBuf> = index
+            .entries()
+            .iter()
+            .map(|entry| gix::path::from_bstr(entry.path(&index)).into_owned())
+            .collect();
+        // Conflicting paths have an entry for each stage
+        paths.dedup();
+        Ok(paths)
+    }
+
+    fn add_config(&self, key: &str, value: &str) -> Result<()> {
+        if self.contains_config(key, value) {
+            return Err(Error::AlreadyExists(value.into()));
+        }
+        let key = Key::parse(key)?;
+        self.edit_local_config(|file| {
+            file.section_mut_or_create_new(key.section, key.subsection)
+                .map_err(|e| anyhow!(e))?
+                .push(key.value_name()?, Some(value.into()));
+            Ok(())
+        })
+    }
+
+    fn contains_config(&self, key: &str, value: &str) -> bool {
+        let entries = self.list_config(key).unwrap_or_default();
+        entries.iter().any(|e| e == value)
+    }
+
+    fn remove_config(&self, key: &str, value: &str) -> Result<()> {
+        if !self.contains_config(key, value) {
+            return Err(Error::NotExist(value.into()));
+        }
+        let key = Key::parse(key)?;
+        self.edit_local_config(|file| {
+            let values = match file.raw_values(key.section, key.subsection, key.name) {
+                Ok(values) => values,
+                // The value is set on a different level, e.g. in the global config
+                Err(_) => return Ok(()),
+            };
+            let indices: Vec<usize> = values
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| v.as_ref() == value.as_bytes())
+                .map(|(i, _)| i)
+                .collect();
+            let mut values = file
+                .raw_values_mut(key.section, key.subsection, key.name)
+                .map_err(|e| anyhow!(e))?;
+            for i in indices.into_iter().rev() {
+                values.delete(i);
+            }
+            Ok(())
+        })
+    }
+
+    fn list_config(&self, key: &str) -> Result<Vec<String>> {
+        let pattern = Regex::new(key).map_err(|e| anyhow!(e))?;
+        let repo = self.repo();
+        let config = repo.config_snapshot();
+        let mut entries = Vec::new();
+
+        for section in config.plumbing().sections() {
+            for (name, value) in section.body().clone() {
+                if pattern.is_match(&full_key(section, name.as_ref())) {
+                    entries.push(value.to_str_lossy().into_owned());
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn list_local_config(&self, key: &str) -> Result<Vec<String>> {
+        let key = Key::parse(key)?;
+        let repo = self.repo();
+        let config = repo.config_snapshot();
+        let values = config.plumbing().raw_values_filter(
+            key.section,
+            key.subsection,
+            key.name,
+            &mut |meta| meta.source == gix::config::Source::Local,
+        );
+        Ok(match values {
+            Ok(values) => values
+                .iter()
+                .map(|v| v.to_str_lossy().into_owned())
+                .collect(),
+            Err(_) => vec![],
+        })
+    }
+
+    fn get_config(&self, key: &str) -> Result<String> {
+        let repo = self.repo();
+        let value = repo
+            .config_snapshot()
+            .string(key)
+            .ok_or_else(|| Error::NotExist(key.into()))?;
+        Ok(value.to_str_lossy().into_owned())
+    }
+
+    fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        let key = Key::parse(key)?;
+        self.edit_local_config(|file| {
+            if let Ok(mut values) = file.raw_values_mut(key.section, key.subsection, key.name) {
+                values.delete_all();
+            }
+            file.section_mut_or_create_new(key.section, key.subsection)
+                .map_err(|e| anyhow!(e))?
+                .push(key.value_name()?, Some(value.into()));
+            Ok(())
+        })
+    }
+
+    fn remove_config_section(&self, key: &str) -> Result<()> {
+        let (section, subsection) = match key.split_once('.') {
+            Some((section, subsection)) => (section, Some(subsection.into())),
+            None => (key, None),
+        };
+        let removed = self.edit_local_config(|file| {
+            let mut removed = false;
+            while file.remove_section(section, subsection).is_some() {
+                removed = true;
+            }
+            Ok(removed)
+        })?;
+        if !removed {
+            return Err(Error::NotExist(key.into()));
+        }
+        Ok(())
+    }
+
+    fn get_attribute(&self, path: &Path, name: &str) -> Result<Option<String>> {
+        let relpath = self.relpath(path)?;
+        let repo = self.repo();
+        let index = repo.index_or_empty().map_err(|e| anyhow!(e))?;
+        let mut stack = repo
+            .attributes_only(
+                &index,
+                gix::worktree::stack::state::attributes::Source::WorktreeThenIdMapping,
+            )
+            .map_err(|e| anyhow!(e))?;
+        let mut outcome = stack.selected_attribute_matches([name]);
+        stack
+            .at_path(relpath, None)?
+            .matching_attributes(&mut outcome);
+
+        let value = outcome
+            .iter_selected()
+            .next()
+            .and_then(|m| match m.assignment.state {
+                gix::attrs::StateRef::Set => Some("set".into()),
+                gix::attrs::StateRef::Unset => Some("unset".into()),
+                gix::attrs::StateRef::Value(v) => Some(v.as_bstr().to_str_lossy().into_owned()),
+                gix::attrs::StateRef::Unspecified => None,
+            });
+        Ok(value)
+    }
+
+    fn refresh_index(&self, paths: &[&Path]) -> Result<()> {
+        super::git_refresh(&self.workdir, paths)
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::{anyhow, Context};
+
+use super::{Error, Repository, Result};
+
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Self {
+        Self::Other(anyhow!(err))
+    }
+}
+
+pub(crate) struct LibGit2Repository {
+    inner: git2::Repository,
+}
+
+impl LibGit2Repository {
+    pub(crate) fn from_current_dir() -> Result<Self> {
+        Self::from_dir(env::current_dir().context("Cannot determine current directory")?)
+    }
+
+    pub(crate) fn from_dir(path: PathBuf) -> Result<Self> {
+        let inner = git2::Repository::discover(&path)
+            .with_context(|| format!("'{}' Not a git repository", path.display()))?;
+        if inner.is_bare() {
+            return Err(anyhow!("Bare repositories are unsupported {}", path.display(),).into());
+        }
+        Ok(Self { inner })
+    }
+
+    fn relpath<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        Ok(path.strip_prefix(self.workdir()).with_context(|| {
+            format!(
+                "Path {} is outside of git repository {}",
+                path.display(),
+                self.workdir().display()
+            )
+        })?)
+    }
+}
+
+impl Repository for LibGit2Repository {
+    fn workdir(&self) -> &Path {
+        self.inner.workdir().unwrap() // None in case of bare repo
+    }
+
+    fn path(&self) -> &Path {
+        self.inner.path()
+    }
+
+    fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relpath(path)?;
+        let head = match self.inner.head() {
+            Ok(head) => head,
+            Err(e) if e.code() == git2::ErrorCode::UnbornBranch => {
+                return Err(Error::NotExist("Repository has no commits yet".into()))
+            }
+            Err(e) => {
+                return Err(anyhow!(e)
+                    .context("Couldn not determine repository head")
+                    .into())
+            }
+        };
+        let entry = head
+            .peel_to_tree()?
+            .get_path(relpath)
+            .map_err(|e| match e.code() {
+                git2::ErrorCode::NotFound => {
+                    Error::NotExist(format!("Path {} is not found in HEAD", relpath.display(),))
+                }
+                _ => Error::Other(e.into()),
+            })?;
+        let contents = entry.to_object(&self.inner)?;
+
+        Ok(contents.as_blob().unwrap().content().into())
+    }
+
+    fn get_index_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relpath(path)?;
+        let mut index = self.inner.index()?;
+        index.read(false)?;
+        let entry = index.get_path(relpath, 0).ok_or_else(|| {
+            Error::NotExist(format!("Path {} is not found in index", relpath.display()))
+        })?;
+        let blob = self.inner.find_blob(entry.id)?;
+
+        Ok(blob.content().into())
+    }
+
+    fn index_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut index = self.inner.index()?;
+        index.read(false)?;
+        let mut paths: Vec<PathBuf> = index
+            .iter()
+            .map(|entry| PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned()))
+            .collect();
+        // Conflicting paths have an entry for each stage
+        paths.dedup();
+        Ok(paths)
+    }
+
+    fn add_config(&self, key: &str, value: &str) -> Result<()> {
+        if self.contains_config(key, value) {
+            return Err(Error::AlreadyExists(value.into()));
+        }
+
+        let mut cfg = self.inner.config()?;
+
+        cfg.set_multivar(key, "^$", value)?;
+
+        Ok(())
+    }
+
+    fn contains_config(&self, key: &str, value: &str) -> bool {
+        let entries = self.list_config(key).unwrap_or_default();
+        entries.iter().any(|e| e == value)
+    }
+
+    fn remove_config(&self, key: &str, value: &str) -> Result<()> {
+        if !self.contains_config(key, value) {
+            return Err(Error::NotExist(value.into()));
+        }
+
+        let mut cfg = self.inner.config()?;
+        let pattern = format!("^{}$", regex::escape(value));
+        cfg.remove_multivar(key, &pattern)?;
+
+        Ok(())
+    }
+
+    fn list_config(&self, key: &str) -> Result<Vec<String>> {
+        let cfg = self.inner.config()?;
+        let mut entries = Vec::new();
+
+        cfg.entries(Some(key))?.for_each(|e| {
+            if let Some(v) = e.value() {
+                entries.push(v.into())
+            }
+        })?;
+
+        Ok(entries)
+    }
+
+    fn list_local_config(&self, key: &str) -> Result<Vec<String>> {
+        let cfg = self.inner.config()?.open_level(git2::ConfigLevel::Local)?;
+        let mut entries = Vec::new();
+
+        cfg.multivar(key, None)?.for_each(|e| {
+            if let Some(v) = e.value() {
+                entries.push(v.into())
+            }
+        })?;
+
+        Ok(entries)
+    }
+
+    fn get_config(&self, key: &str) -> Result<String> {
+        let cfg = self.inner.config()?;
+        cfg.get_string(key)
+            .map_err(|_e| Error::NotExist(key.into()))
+    }
+
+    fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        for v in self.list_config(key)? {
+            self.remove_config(key, &v)?;
+        }
+        let mut cfg = self.inner.config()?;
+        cfg.set_str(key, value)?;
+        Ok(())
+    }
+
+    fn remove_config_section(&self, key: &str) -> Result<()> {
+        // Unfortunately there is no `git config --remove-section <section>` equivalent in libgit2
+        let mut command = process::Command::new("git");
+        command
+            .current_dir(self.workdir())
+            .arg("config")
+            .arg("--remove-section")
+            .arg(key);
+        let output = command.output()?;
+
+        if !output.status.success() {
+            log::error!(
+                "Failed to execute command. This may not be an issue; command='{:?}' status='{}', stdout={:?}, stderr={:?}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            );
+            return Err(Error::NotExist(key.into()));
+        }
+        Ok(())
+    }
+
+    fn get_attribute(&self, path: &Path, name: &str) -> Result<Option<String>> {
+        let relpath = self.relpath(path)?;
+        let value =
+            self.inner
+                .get_attr_bytes(relpath, name, git2::AttrCheckFlags::FILE_THEN_INDEX)?;
+        Ok(match git2::AttrValue::from_bytes(value) {
+            git2::AttrValue::True => Some("set".into()),
+            git2::AttrValue::False => Some("unset".into()),
+            git2::AttrValue::String(v) => Some(v.into()),
+            git2::AttrValue::Bytes(v) => Some(String::from_utf8_lossy(v).into()),
+            git2::AttrValue::Unspecified => None,
+        })
+    }
+
+    fn refresh_index(&self, paths: &[&Path]) -> Result<()> {
+        super::git_refresh(self.workdir(), paths)
+    }
+}
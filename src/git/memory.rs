@@ -0,0 +1,247 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::anyhow;
+use regex::Regex;
+
+use super::{Error, Repository, Result};
+
+/// A [`Repository`] keeping its configuration and `HEAD` tree in memory.
+///
+/// Config keys are matched the same way as libgit2 does: section and
+/// variable names are case-insensitive and [`Repository::list_config`]
+/// treats its argument as a regular expression.
+pub(crate) struct MemoryRepository {
+    workdir: PathBuf,
+    path: PathBuf,
+    head: RefCell<Option<HashMap<PathBuf, Vec<u8>>>>,
+    index: RefCell<HashMap<PathBuf, Vec<Vec<u8>>>>,
+    config: RefCell<Vec<(String, String)>>,
+    attributes: RefCell<HashMap<(PathBuf, String), String>>,
+}
+
+impl MemoryRepository {
+    pub(crate) fn new(workdir: impl Into<PathBuf>) -> Self {
+        let workdir = workdir.into();
+        let path = workdir.join(".git");
+        Self {
+            workdir,
+            path,
+            head: RefCell::new(None),
+            index: RefCell::new(HashMap::new()),
+            config: RefCell::new(vec![]),
+            attributes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Stores `contents` under `path` (relative to the working directory) in the index and in
+    /// the `HEAD` tree
+    pub(crate) fn commit(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        let contents = contents.into();
+        self.stage(&path, contents.clone());
+        self.head
+            .borrow_mut()
+            .get_or_insert_with(HashMap::new)
+            .insert(path.as_ref().into(), contents);
+    }
+
+    /// Stores `contents` under `path` (relative to the working directory) in the index
+    pub(crate) fn stage(&self, path: impl AsRef<Path>, contents: impl Into<Vec<u8>>) {
+        self.index
+            .borrow_mut()
+            .insert(path.as_ref().into(), vec![contents.into()]);
+    }
+
+    /// Records a merge conflict for `path` with the `base`, `ours` and `theirs` stages
+    pub(crate) fn conflict(
+        &self,
+        path: impl AsRef<Path>,
+        base: impl Into<Vec<u8>>,
+        ours: impl Into<Vec<u8>>,
+        theirs: impl Into<Vec<u8>>,
+    ) {
+        self.index.borrow_mut().insert(
+            path.as_ref().into(),
+            vec![base.into(), ours.into(), theirs.into()],
+        );
+    }
+
+    /// Assigns `value` to the attribute `name` of `path` (relative to the working directory)
+    pub(crate) fn set_attribute(&self, path: impl AsRef<Path>, name: &str, value: &str) {
+        self.attributes
+            .borrow_mut()
+            .insert((path.as_ref().into(), name.into()), value.into());
+    }
+
+    fn relpath<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        path.strip_prefix(&self.workdir).map_err(|_| {
+            anyhow!(
+                "Path {} is outside of git repository {}",
+                path.display(),
+                self.workdir.display()
+            )
+            .into()
+        })
+    }
+}
+
+fn normalize_key(key: &str) -> Result<String> {
+    let (section, rest) = key
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Invalid config key '{}'", key))?;
+    let (subsection, name) = match rest.rsplit_once('.') {
+        Some((subsection, name)) => (Some(subsection), name),
+        None => (None, rest),
+    };
+    if name.is_empty() {
+        return Err(anyhow!("Invalid config key '{}'", key).into());
+    }
+    Ok(match subsection {
+        Some(subsection) => format!(
+            "{}.{}.{}",
+            section.to_lowercase(),
+            subsection,
+            name.to_lowercase()
+        ),
+        None => format!("{}.{}", section.to_lowercase(), name.to_lowercase()),
+    })
+}
+
+impl Repository for MemoryRepository {
+    fn workdir(&self) -> &Path {
+        &self.workdir
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relpath(path)?;
+        let head = self.head.borrow();
+        let tree = head
+            .as_ref()
+            .ok_or_else(|| Error::NotExist("Repository has no commits yet".into()))?;
+        tree.get(relpath).cloned().ok_or_else(|| {
+            Error::NotExist(format!("Path {} is not found in HEAD", relpath.display()))
+        })
+    }
+
+    fn get_index_contents(&self, path: &Path) -> Result<Vec<u8>> {
+        let relpath = self.relpath(path)?;
+        match self.index.borrow().get(relpath).map(|stages| &stages[..]) {
+            Some([contents]) => Ok(contents.clone()),
+            _ => Err(Error::NotExist(format!(
+                "Path {} is not found in index",
+                relpath.display()
+            ))),
+        }
+    }
+
+    fn index_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = self.index.borrow().keys().cloned().collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn add_config(&self, key: &str, value: &str) -> Result<()> {
+        if self.contains_config(key, value) {
+            return Err(Error::AlreadyExists(value.into()));
+        }
+        let key = normalize_key(key)?;
+        self.config.borrow_mut().push((key, value.into()));
+        Ok(())
+    }
+
+    fn contains_config(&self, key: &str, value: &str) -> bool {
+        let entries = self.list_config(key).unwrap_or_default();
+        entries.iter().any(|e| e == value)
+    }
+
+    fn remove_config(&self, key: &str, value: &str) -> Result<()> {
+        if !self.contains_config(key, value) {
+            return Err(Error::NotExist(value.into()));
+        }
+        let key = normalize_key(key)?;
+        self.config
+            .borrow_mut()
+            .retain(|(k, v)| !(k == &key && v == value));
+        Ok(())
+    }
+
+    fn list_config(&self, key: &str) -> Result<Vec<String>> {
+        let pattern = Regex::new(key).map_err(|e| anyhow!(e))?;
+        Ok(self
+            .config
+            .borrow()
+            .iter()
+            .filter(|(k, _)| pattern.is_match(k))
+            .map(|(_, v)| v.clone())
+            .collect())
+    }
+
+    fn list_local_config(&self, key: &str) -> Result<Vec<String>> {
+        let key = normalize_key(key)?;
+        Ok(self
+            .config
+            .borrow()
+            .iter()
+            .filter(|(k, _)| k == &key)
+            .map(|(_, v)| v.clone())
+            .collect())
+    }
+
+    fn get_config(&self, key: &str) -> Result<String> {
+        let normalized = normalize_key(key).map_err(|_| Error::NotExist(key.into()))?;
+        self.config
+            .borrow()
+            .iter()
+            .rev()
+            .find(|(k, _)| k == &normalized)
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| Error::NotExist(key.into()))
+    }
+
+    fn set_config(&self, key: &str, value: &str) -> Result<()> {
+        let key = normalize_key(key)?;
+        let mut config = self.config.borrow_mut();
+        config.retain(|(k, _)| k != &key);
+        config.push((key, value.into()));
+        Ok(())
+    }
+
+    fn remove_config_section(&self, key: &str) -> Result<()> {
+        let prefix = format!("{}.", normalize_section(key));
+        let mut config = self.config.borrow_mut();
+        let len = config.len();
+        config.retain(|(k, _)| !k.starts_with(&prefix));
+        if config.len() == len {
+            return Err(Error::NotExist(key.into()));
+        }
+        Ok(())
+    }
+
+    fn get_attribute(&self, path: &Path, name: &str) -> Result<Option<String>> {
+        let relpath = self.relpath(path)?;
+        Ok(self
+            .attributes
+            .borrow()
+            .get(&(relpath.into(), name.into()))
+            .cloned())
+    }
+
+    fn refresh_index(&self, _paths: &[&Path]) -> Result<()> {
+        // There is no stat information to refresh and filters don't run in memory
+        Ok(())
+    }
+}
+
+fn normalize_section(section: &str) -> String {
+    match section.split_once('.') {
+        Some((section, subsection)) => format!("{}.{}", section.to_lowercase(), subsection),
+        None => section.to_lowercase(),
+    }
+}
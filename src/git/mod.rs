@@ -0,0 +1,477 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::anyhow;
+use thiserror::Error;
+
+#[cfg(feature = "gitoxide")]
+mod gitoxide;
+#[cfg(feature = "libgit2")]
+#[cfg_attr(feature = "gitoxide", allow(dead_code))]
+mod libgit2;
+#[cfg(test)]
+mod memory;
+
+#[cfg(feature = "gitoxide")]
+pub(crate) use gitoxide::GixRepository;
+#[cfg(feature = "libgit2")]
+#[cfg_attr(feature = "gitoxide", allow(unused_imports))]
+pub(crate) use libgit2::LibGit2Repository;
+#[cfg(test)]
+pub(crate) use memory::MemoryRepository;
+
+#[cfg(not(any(feature = "libgit2", feature = "gitoxide")))]
+compile_error!("At least one of the `libgit2` or `gitoxide` features has to be enabled");
+
+/// The backend used by the application. `gitoxide` is preferred when both are enabled.
+#[cfg(feature = "gitoxide")]
+pub(crate) type DefaultRepository = GixRepository;
+#[cfg(not(feature = "gitoxide"))]
+pub(crate) type DefaultRepository = LibGit2Repository;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{:?} already exists.", .0)]
+    AlreadyExists(String),
+    #[error("{:?} doesn't exist.", .0)]
+    NotExist(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Other(anyhow!(err))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub(crate) trait Repository {
+    fn workdir(&self) -> &Path;
+
+    fn path(&self) -> &Path;
+
+    /// Reads the contents of `path` as committed in `HEAD`
+    fn get_file_contents(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Reads the contents of `path` as staged in the index
+    ///
+    /// Only unconflicted entries are considered, so during a merge conflicting paths are reported
+    /// as not existing.
+    fn get_index_contents(&self, path: &Path) -> Result<Vec<u8>>;
+
+    /// Lists the paths in the index relative to the working directory, in sorted order
+    fn index_paths(&self) -> Result<Vec<PathBuf>>;
+
+    fn add_config(&self, key: &str, value: &str) -> Result<()>;
+
+    fn contains_config(&self, key: &str, value: &str) -> bool;
+
+    fn remove_config(&self, key: &str, value: &str) -> Result<()>;
+
+    fn list_config(&self, key: &str) -> Result<Vec<String>>;
+
+    /// Lists the values of exactly `key` in the config of the repository itself, ignoring the
+    /// global and system levels
+    fn list_local_config(&self, key: &str) -> Result<Vec<String>>;
+
+    fn get_config(&self, key: &str) -> Result<String>;
+
+    fn set_config(&self, key: &str, value: &str) -> Result<()>;
+
+    fn remove_config_section(&self, key: &str) -> Result<()>;
+
+    /// Looks up the attribute `name` for `path` the same way as `git check-attr` does.
+    ///
+    /// Returns `"set"`, `"unset"` or the assigned value, and `None` if the attribute is unspecified.
+    fn get_attribute(&self, path: &Path, name: &str) -> Result<Option<String>>;
+
+    /// Refreshes the stat information of `paths` in the index the same way as
+    /// `git add --refresh` does, without staging their contents.
+    ///
+    /// Used after rewriting files whose cleaned contents didn't change, so that git doesn't
+    /// consider them modified. The paths must already be in the index.
+    fn refresh_index(&self, paths: &[&Path]) -> Result<()>;
+}
+
+/// Runs `git add --refresh` on `paths`, neither libgit2 nor gitoxide run the configured filter
+/// drivers
+fn git_refresh(workdir: &Path, paths: &[&Path]) -> Result<()> {
+    let output = process::Command::new("git")
+        .current_dir(workdir)
+        .args(["add", "--refresh", "--"])
+        .args(paths)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Couldn't refresh the index: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, ops::Deref};
+
+    use anyhow::Result;
+    use assert_fs::prelude::*;
+    use assert_fs::TempDir;
+    use assert_matches::assert_matches;
+    use duct::cmd;
+    use rstest::fixture;
+    use rstest::rstest;
+
+    use super::*;
+
+    #[fixture]
+    fn tempdir() -> TempDir {
+        TempDir::new().unwrap()
+    }
+
+    type Open = fn(PathBuf) -> super::Result<Box<dyn Repository>>;
+
+    #[cfg(feature = "libgit2")]
+    fn libgit2(path: PathBuf) -> super::Result<Box<dyn Repository>> {
+        Ok(Box::new(LibGit2Repository::from_dir(path)?))
+    }
+
+    #[cfg(feature = "gitoxide")]
+    fn gitoxide(path: PathBuf) -> super::Result<Box<dyn Repository>> {
+        Ok(Box::new(GixRepository::from_dir(path)?))
+    }
+
+    struct Repo {
+        inner: Box<dyn Repository>,
+        dir: TempDir,
+    }
+
+    impl Deref for Repo {
+        type Target = dyn Repository;
+
+        fn deref(&self) -> &Self::Target {
+            self.inner.as_ref()
+        }
+    }
+
+    fn git_repo(tempdir: TempDir, open: Open) -> Repo {
+        cmd!("git", "init").dir(tempdir.path()).run().unwrap();
+        let repo = open(tempdir.path().to_path_buf()).unwrap();
+        Repo {
+            inner: repo,
+            dir: tempdir,
+        }
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_repo_can_be_loaded(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        assert_eq!(git_repo.workdir(), git_repo.dir.path());
+        assert_eq!(git_repo.path(), git_repo.dir.join(".git"));
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_repo_required(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let repo = open(tempdir.path().to_path_buf()).err();
+        assert_matches!(repo, Some(Error::Other(_)));
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_bare_repo_is_error(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        cmd!("git", "init", "--bare").dir(tempdir.path()).run()?;
+        let repo = open(tempdir.path().to_path_buf()).err();
+        assert_matches!(repo, Some(Error::Other(_)));
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_get_file_contents(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        cmd!("git", "config", "user.email", "author@example.com")
+            .dir(git_repo.dir.path())
+            .run()?;
+        cmd!("git", "config", "user.name", "A U Thor")
+            .dir(git_repo.dir.path())
+            .run()?;
+        let path = PathBuf::from("subdir/file.txt");
+        let file_contents = "file contents";
+
+        let repo_file = git_repo.dir.child(&path);
+        repo_file.touch()?;
+        repo_file.write_str(file_contents)?;
+        cmd!("git", "add", &path).dir(git_repo.dir.path()).run()?;
+        cmd!("git", "commit", "-m", "adding file")
+            .dir(git_repo.dir.path())
+            .run()?;
+
+        assert_eq!(
+            git_repo.get_file_contents(&git_repo.dir.join(&path))?,
+            file_contents.as_bytes()
+        );
+
+        repo_file.write_str("additional_contents")?;
+        assert_eq!(
+            git_repo.get_file_contents(&git_repo.dir.join(&path))?,
+            file_contents.as_bytes()
+        );
+
+        assert!(git_repo
+            .get_file_contents(git_repo.dir.path().parent().unwrap())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_config(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        // At first there are no entries under the "foo" section
+        assert_eq!(git_repo.list_config("foo")?, [] as [String; 0]);
+        assert!(!git_repo.contains_config("foo.bar", "foobar"));
+
+        assert_matches!(git_repo.set_config("invalidkey", "foobar"), Err(_));
+
+        // Both of these are added to the config
+        git_repo.set_config("foo.bar", "foobar")?;
+        git_repo.add_config("foo.bar", "snafu")?;
+
+        assert!(git_repo.contains_config("foo.bar", "foobar"));
+        assert!(git_repo.contains_config("foo.bar", "snafu"));
+        assert_eq!(git_repo.list_config("foo")?, ["foobar", "snafu"]);
+
+        // Returns the last set config
+        assert_eq!(git_repo.get_config("foo.bar")?, "snafu");
+
+        // Set overrides multivalue entries
+        git_repo.set_config("foo.bar", "FOOBAR")?;
+
+        assert!(git_repo.contains_config("foo.bar", "FOOBAR"));
+        assert_eq!(git_repo.list_config("foo")?, ["FOOBAR"]);
+
+        git_repo.set_config("foo.bar", "foobar")?;
+
+        // Duplicate value cannot be added
+        assert_matches!(
+            git_repo.add_config("foo.bar", "foobar"),
+            Err(Error::AlreadyExists(value)) if value == "foobar"
+        );
+
+        git_repo.remove_config("foo.bar", "foobar")?;
+
+        // Absent value cannot be removed
+        assert_matches!(
+            git_repo.remove_config("foo.bar", "foobar"), Err(Error::NotExist(value)) if value == "foobar"
+        );
+
+        assert_matches!(git_repo.remove_config_section("foo"), Ok(()));
+        assert_eq!(git_repo.list_config("foo")?, [] as [String; 0]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_config_locked(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        let config = fs::read_to_string(git_repo.path().join("config"))?;
+        git_repo.dir.child(".git/config.lock").touch()?;
+
+        assert!(git_repo.add_config("foo.bar", "foobar").is_err());
+        assert_eq!(fs::read_to_string(git_repo.path().join("config"))?, config);
+        assert!(git_repo.path().join("config.lock").exists());
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_list_local_config(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        assert_eq!(
+            git_repo.list_local_config("git-agecrypt.config.trusted")?,
+            [] as [String; 0]
+        );
+
+        git_repo.add_config("git-agecrypt.config.trusted", "foo")?;
+        git_repo.add_config("git-agecrypt.config.trusted", "bar")?;
+        // Keys which the unanchored pattern of `list_config` would match
+        git_repo.add_config("git-agecrypt.config.untrusted", "decoy")?;
+        git_repo.add_config("foo.trusted", "decoy")?;
+        git_repo.add_config("git-agecrypt.configXtrusted.x", "decoy")?;
+
+        assert_eq!(
+            git_repo.list_local_config("git-agecrypt.config.trusted")?,
+            ["foo", "bar"]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_get_attribute(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        git_repo.dir.child(".gitattributes").write_str(
+            "secrets/** filter=git-agecrypt diff=git-agecrypt\n*.bin -text\n*.txt text\n",
+        )?;
+
+        assert_eq!(
+            git_repo.get_attribute(&git_repo.dir.join("secrets/a/b"), "filter")?,
+            Some("git-agecrypt".into())
+        );
+        assert_eq!(
+            git_repo.get_attribute(&git_repo.dir.join("file.bin"), "text")?,
+            Some("unset".into())
+        );
+        assert_eq!(
+            git_repo.get_attribute(&git_repo.dir.join("file.txt"), "text")?,
+            Some("set".into())
+        );
+        assert_eq!(
+            git_repo.get_attribute(&git_repo.dir.join("file.txt"), "filter")?,
+            None
+        );
+
+        assert!(git_repo
+            .get_attribute(git_repo.dir.path().parent().unwrap(), "filter")
+            .is_err());
+        Ok(())
+    }
+
+    fn git(git_repo: &Repo, args: &[&str]) -> Result<()> {
+        cmd("git", args)
+            .dir(git_repo.dir.path())
+            .stdout_null()
+            .stderr_null()
+            .run()?;
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_get_index_contents(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        git(&git_repo, &["config", "user.email", "author@example.com"])?;
+        git(&git_repo, &["config", "user.name", "A U Thor"])?;
+        let path = git_repo.dir.join("file.txt");
+        let repo_file = git_repo.dir.child("file.txt");
+
+        assert_matches!(git_repo.get_index_contents(&path), Err(Error::NotExist(_)));
+
+        repo_file.write_str("staged")?;
+        git(&git_repo, &["add", "file.txt"])?;
+        repo_file.write_str("working copy")?;
+
+        // There is no HEAD before the initial commit
+        assert_matches!(git_repo.get_file_contents(&path), Err(Error::NotExist(_)));
+        assert_eq!(git_repo.get_index_contents(&path)?, b"staged");
+
+        git(&git_repo, &["commit", "-m", "initial"])?;
+        repo_file.write_str("staged again")?;
+        git(&git_repo, &["add", "file.txt"])?;
+
+        assert_eq!(git_repo.get_file_contents(&path)?, b"staged");
+        assert_eq!(git_repo.get_index_contents(&path)?, b"staged again");
+
+        assert!(git_repo
+            .get_index_contents(git_repo.dir.path().parent().unwrap())
+            .is_err());
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_get_index_contents_during_merge(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        git(&git_repo, &["config", "user.email", "author@example.com"])?;
+        git(&git_repo, &["config", "user.name", "A U Thor"])?;
+        let path = git_repo.dir.join("file.txt");
+        let repo_file = git_repo.dir.child("file.txt");
+
+        repo_file.write_str("base")?;
+        git(&git_repo, &["add", "file.txt"])?;
+        git(&git_repo, &["commit", "-m", "base"])?;
+        git(&git_repo, &["checkout", "-b", "theirs"])?;
+        repo_file.write_str("theirs")?;
+        git(&git_repo, &["commit", "-am", "theirs"])?;
+        git(&git_repo, &["checkout", "-"])?;
+        repo_file.write_str("ours")?;
+        git(&git_repo, &["commit", "-am", "ours"])?;
+        assert!(git(&git_repo, &["merge", "theirs"]).is_err());
+
+        assert_matches!(git_repo.get_index_contents(&path), Err(Error::NotExist(_)));
+        assert_eq!(git_repo.get_file_contents(&path)?, b"ours");
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_index_paths(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        assert_eq!(git_repo.index_paths()?, [] as [PathBuf; 0]);
+
+        git_repo.dir.child("b.txt").write_str("b")?;
+        git_repo.dir.child("sub/a.txt").write_str("a")?;
+        git_repo.dir.child("untracked.txt").write_str("c")?;
+        cmd!("git", "add", "b.txt", "sub/a.txt")
+            .dir(git_repo.dir.path())
+            .run()?;
+
+        assert_eq!(
+            git_repo.index_paths()?,
+            [PathBuf::from("b.txt"), PathBuf::from("sub/a.txt")]
+        );
+        Ok(())
+    }
+
+    #[rstest]
+    #[cfg_attr(feature = "libgit2", case::libgit2(libgit2))]
+    #[cfg_attr(feature = "gitoxide", case::gitoxide(gitoxide))]
+    fn test_refresh_index(tempdir: TempDir, #[case] open: Open) -> Result<()> {
+        let git_repo = git_repo(tempdir, open);
+        git_repo.dir.child("file.txt").write_str("file contents")?;
+        cmd!("git", "add", "file.txt")
+            .dir(git_repo.dir.path())
+            .run()?;
+        git_repo
+            .dir
+            .child("file.txt")
+            .write_str("changed contents")?;
+
+        // Changed contents aren't staged
+        git_repo.refresh_index(&[Path::new("file.txt")])?;
+        assert_eq!(
+            git_repo.get_index_contents(&git_repo.dir.join("file.txt"))?,
+            b"file contents"
+        );
+
+        git_repo.dir.child("untracked.txt").write_str("untracked")?;
+        assert_matches!(
+            git_repo.refresh_index(&[Path::new("untracked.txt")]),
+            Err(_)
+        );
+        Ok(())
+    }
+}
@@ -1,16 +1,24 @@
 mod age;
+#[cfg(unix)]
+mod agent;
 mod cli;
 mod config;
 mod ctx;
+mod frame;
 mod git;
+mod secret;
+mod sign;
 
 use anyhow::Result;
 use cli::run;
 
 fn main() -> Result<()> {
     env_logger::init();
-    let args = cli::parse_args();
-    let repo = git::LibGit2Repository::from_current_dir()?;
+    let args = match cli::run_standalone(cli::parse_args())? {
+        Some(args) => args,
+        None => return Ok(()),
+    };
+    let repo = git::DefaultRepository::from_current_dir()?;
     let ctx = ctx::new(repo);
 
     run(args, ctx)
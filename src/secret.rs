@@ -0,0 +1,45 @@
+//! Buffers of plaintexts and key material, wiped from memory when they are dropped
+
+use std::io::{self, ErrorKind, Read};
+
+use zeroize::Zeroizing;
+
+/// Bytes which are zeroed when dropped
+pub(crate) type SecretBytes = Zeroizing<Vec<u8>>;
+
+/// Reads `reader` to the end like [`Read::read_to_end`]
+///
+/// The buffer is grown by moving the contents to a larger one and wiping the old one, instead
+/// of reallocating it in place, which would leave copies of the contents in freed memory.
+pub(crate) fn read_to_end(mut reader: impl Read) -> io::Result<SecretBytes> {
+    let mut rv = SecretBytes::default();
+    let mut chunk = Zeroizing::new([0u8; 8192]);
+    loop {
+        let n = match reader.read(&mut chunk[..]) {
+            Ok(0) => return Ok(rv),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if rv.capacity() - rv.len() < n {
+            let mut grown =
+                Zeroizing::new(Vec::with_capacity((rv.len() + n).max(rv.capacity() * 2)));
+            grown.extend_from_slice(&rv);
+            rv = grown;
+        }
+        rv.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_to_end() -> io::Result<()> {
+        let contents: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        assert_eq!(*read_to_end(&contents[..])?, contents);
+        assert!(read_to_end(io::empty())?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+//! Detached SSH signatures of committed ciphertexts
+//!
+//! Signatures are made and checked by `ssh-keygen -Y` in the `git-agecrypt` namespace. A signed
+//! blob is the armored signature followed by the ciphertext, so that it can also be checked by
+//! hand with `ssh-keygen -Y verify`.
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// Namespace of the signatures, so that they can't be mistaken for e.g. signed commits
+const NAMESPACE: &str = "git-agecrypt";
+const BEGIN: &[u8] = b"-----BEGIN SSH SIGNATURE-----\n";
+const END: &[u8] = b"-----END SSH SIGNATURE-----\n";
+
+/// Splits `blob` into its signature, if any, and the ciphertext
+pub(crate) fn split(blob: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    if !blob.starts_with(BEGIN) {
+        return (None, blob);
+    }
+    match blob.windows(END.len()).position(|w| w == END) {
+        Some(i) => {
+            let (signature, ciphertext) = blob.split_at(i + END.len());
+            (Some(signature), ciphertext)
+        }
+        None => (None, blob),
+    }
+}
+
+/// The ciphertext of `blob`, without its signature
+pub(crate) fn payload(blob: &[u8]) -> &[u8] {
+    split(blob).1
+}
+
+/// Signs `ciphertext` with the SSH `key`, returns the signed blob
+///
+/// `key` can also be a public key whose private key is loaded into `ssh-agent`.
+pub(crate) fn sign(key: &Path, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let mut command = Command::new("ssh-keygen");
+    command.args(["-Y", "sign", "-n", NAMESPACE, "-f"]).arg(key);
+    let mut rv = run(&mut command, ciphertext)
+        .with_context(|| format!("Couldn't sign with '{}'", key.display()))?;
+    if !rv.starts_with(BEGIN) || !rv.ends_with(END) {
+        bail!("Unexpected output of ssh-keygen when signing");
+    }
+    rv.extend_from_slice(ciphertext);
+    Ok(rv)
+}
+
+/// Checks `signature` of `ciphertext` against the `allowed_signers` file in the format of
+/// `ssh-keygen`, returns the principal who made it
+pub(crate) fn verify(
+    allowed_signers: &Path,
+    signature: &[u8],
+    ciphertext: &[u8],
+) -> Result<String> {
+    let mut file = tempfile::NamedTempFile::new()?;
+    file.write_all(signature)?;
+
+    let principals = run(
+        Command::new("ssh-keygen")
+            .args(["-Y", "find-principals", "-f"])
+            .arg(allowed_signers)
+            .arg("-s")
+            .arg(file.path()),
+        b"",
+    )
+    .context("The key of the signature isn't an allowed signer")?;
+    let principals = String::from_utf8_lossy(&principals);
+    let Some(principal) = principals.lines().next() else {
+        bail!("The key of the signature isn't an allowed signer");
+    };
+
+    run(
+        Command::new("ssh-keygen")
+            .args(["-Y", "verify", "-n", NAMESPACE, "-f"])
+            .arg(allowed_signers)
+            .args(["-I", principal, "-s"])
+            .arg(file.path()),
+        ciphertext,
+    )
+    .context("Invalid signature")?;
+    Ok(principal.into())
+}
+
+/// Runs `command` with `input` as its standard input, returns its standard output
+fn run(command: &mut Command, input: &[u8]) -> Result<Vec<u8>> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Couldn't run ssh-keygen")?;
+    let mut stdin = child.stdin.take().expect("Standard input is piped");
+    // Written from another thread, so that a large output doesn't block the child
+    let output = thread::scope(|s| {
+        s.spawn(move || stdin.write_all(input));
+        child.wait_with_output()
+    })?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_fs::{prelude::*, TempDir};
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    fn keygen(dir: &TempDir, name: &str) -> std::path::PathBuf {
+        let key = dir.join(name);
+        duct::cmd!("ssh-keygen", "-q", "-t", "ed25519", "-N", "", "-f", &key)
+            .run()
+            .unwrap();
+        key
+    }
+
+    #[test]
+    fn test_sign_verify() -> Result<()> {
+        let dir = TempDir::new()?;
+        let alice = keygen(&dir, "alice");
+        let mallory = keygen(&dir, "mallory");
+        let public_key = std::fs::read_to_string(alice.with_extension("pub"))?;
+        dir.child("allowed_signers")
+            .write_str(&format!("alice@example.com {}", public_key))?;
+        let allowed_signers = dir.join("allowed_signers");
+
+        let signed = sign(&alice, b"ciphertext")?;
+        let (signature, ciphertext) = split(&signed);
+        assert_eq!(ciphertext, b"ciphertext");
+        assert_eq!(
+            verify(&allowed_signers, signature.unwrap(), ciphertext)?,
+            "alice@example.com"
+        );
+        assert_matches!(
+            verify(&allowed_signers, signature.unwrap(), b"forged"),
+            Err(_)
+        );
+
+        let forged = sign(&mallory, b"ciphertext")?;
+        assert_matches!(
+            verify(&allowed_signers, split(&forged).0.unwrap(), b"ciphertext"),
+            Err(_)
+        );
+
+        assert_eq!(split(b"ciphertext"), (None, &b"ciphertext"[..]));
+        Ok(())
+    }
+}